@@ -6,7 +6,7 @@ use rust_libretro::{
 
 use viennetta_gb::hardware::{io::{cart::Cartridge, HEIGHT, WIDTH, LcdPixels}, GameBoy};
 use viennetta_gb::hardware::io::joypad::Buttons;
-use viennetta_gb::hardware::io::apu::SAMPLE_RATE;
+use viennetta_gb::hardware::io::apu::{Channels, DEFAULT_SAMPLE_RATE};
 use viennetta_gb::hardware::cpu::CPU;
 
 const PIXEL_SIZE: usize = 4;
@@ -46,8 +46,74 @@ fn convert_vec_to_c_point(data: &Vec<u8>) -> *mut c_void {
 } 
 
 #[derive(CoreOptions)]
+#[categories({
+    "audio_settings",
+    "Audio",
+    "Options related to audio output."
+})]
+#[options({
+    "viennetta_sample_rate",
+    "Audio > Sample Rate",
+    "Sample Rate",
+    "Setting 'Audio > Sample Rate' changes how many audio samples per second the core generates.",
+    "Setting 'Sample Rate' changes how many audio samples per second the core generates.",
+    "audio_settings",
+    {
+        { "48000" },
+        { "44100" },
+        { "32000" },
+        { "96000" },
+    }
+}, {
+    "viennetta_mute_square1",
+    "Audio > Mute Square 1",
+    "Mute Square 1",
+    "Silences the first square wave channel.",
+    "Silences the first square wave channel.",
+    "audio_settings",
+    {
+        { "disabled" },
+        { "enabled" },
+    }
+}, {
+    "viennetta_mute_square2",
+    "Audio > Mute Square 2",
+    "Mute Square 2",
+    "Silences the second square wave channel.",
+    "Silences the second square wave channel.",
+    "audio_settings",
+    {
+        { "disabled" },
+        { "enabled" },
+    }
+}, {
+    "viennetta_mute_wave",
+    "Audio > Mute Wave",
+    "Mute Wave",
+    "Silences the wave channel.",
+    "Silences the wave channel.",
+    "audio_settings",
+    {
+        { "disabled" },
+        { "enabled" },
+    }
+}, {
+    "viennetta_mute_noise",
+    "Audio > Mute Noise",
+    "Mute Noise",
+    "Silences the noise channel.",
+    "Silences the noise channel.",
+    "audio_settings",
+    {
+        { "disabled" },
+        { "enabled" },
+    }
+})]
 struct ViennettaCore {
     gameboy: GameBoy,
+    sample_rate: u32,
+    muted: Channels,
+    av_info_changed: bool,
 }
 
 impl Core for ViennettaCore {
@@ -63,19 +129,32 @@ impl Core for ViennettaCore {
     }
 
     fn on_get_av_info(&mut self, _ctx: &mut GetAvInfoContext) -> retro_system_av_info {
-        retro_system_av_info {
-            geometry: retro_game_geometry {
-                base_width: WIDTH as u32,
-                base_height: HEIGHT as u32,
-                max_width: WIDTH as u32,
-                max_height: HEIGHT as u32,
-                aspect_ratio: 0.0,
-            },
-            timing: retro_system_timing {
-                fps: 60.0,
-                sample_rate: SAMPLE_RATE as f64,
-            },
+        self.get_av_info()
+    }
+
+    fn on_options_changed(&mut self, ctx: &mut OptionsChangedContext) {
+        if let Some(sample_rate) = ctx.get_variable("viennetta_sample_rate").and_then(|value| value.parse().ok()) {
+            if sample_rate != self.sample_rate {
+                self.sample_rate = sample_rate;
+                self.gameboy.set_sample_rate(sample_rate);
+                self.av_info_changed = true;
+            }
+        }
+
+        let mutes = [
+            ("viennetta_mute_square1", Channels::Square1),
+            ("viennetta_mute_square2", Channels::Square2),
+            ("viennetta_mute_wave", Channels::Wave),
+            ("viennetta_mute_noise", Channels::Noise),
+        ];
+        for (key, channel) in mutes {
+            match ctx.get_variable(key) {
+                Some("enabled") => self.muted.insert(channel),
+                Some("disabled") => self.muted.remove(channel),
+                _ => (),
+            }
         }
+        self.gameboy.mmu.apu.muted = self.muted;
     }
 
     fn on_set_environment(&mut self, initial: bool, ctx: &mut SetEnvironmentContext) {
@@ -103,6 +182,8 @@ impl Core for ViennettaCore {
 
             let data = convert_c_point_to_vec(game.data, game.size);
             self.gameboy = GameBoy::new(Cartridge::new(&data));
+            self.gameboy.set_sample_rate(self.sample_rate);
+            self.gameboy.mmu.apu.muted = self.muted;
         }
         Ok(())
     }
@@ -127,13 +208,17 @@ impl Core for ViennettaCore {
 
     #[inline]
     fn on_run(&mut self, ctx: &mut RunContext, _delta_us: Option<i64>) {
+        if self.av_info_changed {
+            ctx.set_system_av_info(self.get_av_info());
+        }
+
         self.update_gb_joypad(ctx);
         let pixels = convert_gameboy_to_rgb565(self.gameboy.run_frame());
         ctx.draw_frame(&pixels, WIDTH as u32, HEIGHT as u32, WIDTH as usize * 4);
 
         let actx: AudioContext = ctx.into();
         actx.batch_audio_samples(&self.gameboy.mmu.apu.sample_buf);
-        self.gameboy.mmu.apu.sample_buf = vec![];
+        self.gameboy.mmu.apu.sample_buf.clear();
     }
 
     fn on_write_audio(&mut self, ctx: &mut AudioContext) {
@@ -148,31 +233,53 @@ impl Core for ViennettaCore {
         // //println!("before audio");
         // //println!("{}", self.gameboy.mmu.apu.sample_buf.len());
         // ctx.batch_audio_samples(&self.gameboy.mmu.apu.sample_buf);
-        // self.gameboy.mmu.apu.sample_buf = vec![];
+        // self.gameboy.mmu.apu.sample_buf.clear();
         // //println!("after audio");
     }
 }
 
 impl ViennettaCore {
+    fn get_av_info(&mut self) -> retro_system_av_info {
+        self.av_info_changed = false;
+
+        retro_system_av_info {
+            geometry: retro_game_geometry {
+                base_width: WIDTH as u32,
+                base_height: HEIGHT as u32,
+                max_width: WIDTH as u32,
+                max_height: HEIGHT as u32,
+                aspect_ratio: 0.0,
+            },
+            timing: retro_system_timing {
+                fps: 60.0,
+                sample_rate: self.sample_rate as f64,
+            },
+        }
+    }
+
     fn update_gb_joypad(&mut self, ctx: &mut RunContext) {
         let buttons = [
-            JoypadState::RIGHT, JoypadState::LEFT, JoypadState::UP, JoypadState::DOWN,
-            JoypadState::A, JoypadState::B, JoypadState::SELECT, JoypadState::START,
-            
+            (JoypadState::RIGHT, Buttons::Right), (JoypadState::LEFT, Buttons::Left),
+            (JoypadState::UP, Buttons::Up), (JoypadState::DOWN, Buttons::Down),
+            (JoypadState::A, Buttons::A), (JoypadState::B, Buttons::B),
+            (JoypadState::SELECT, Buttons::Select), (JoypadState::START, Buttons::Start),
         ];
         let joypad = ctx.get_joypad_state(0, 0);
-        let mut gb_buttons = 0xFF;
+        let mut gb_buttons = Buttons::empty();
 
-        for (i, button) in buttons.iter().enumerate() {
-            if joypad.contains(*button) {
-                gb_buttons &= !(1 << i);
+        for (retro_button, button) in buttons {
+            if joypad.contains(retro_button) {
+                gb_buttons |= button;
             }
         }
 
-        self.gameboy.mmu.joypad.update_state(Buttons::from_bits(gb_buttons).unwrap());
+        self.gameboy.mmu.joypad.set_pressed(gb_buttons);
     }
 }
 
 retro_core!(ViennettaCore {
     gameboy: GameBoy::new(Cartridge::new(&[0; 0x8000])),
+    sample_rate: DEFAULT_SAMPLE_RATE,
+    muted: Channels::empty(),
+    av_info_changed: false,
 });
\ No newline at end of file
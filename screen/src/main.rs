@@ -49,19 +49,21 @@ fn convert_gameboy_to_fb(gameboy: LcdPixels, width: usize, height: usize) -> Vec
 #[cfg(target_os = "linux")]
 fn update_gb_joypad(gameboy: GameBoy, device_state: DeviceState) {
     let buttons = [
-        Keycode::RIGHT, Keycode::LEFT, Keycode::UP, Keycode::DOWN,
-        Keycode::A, Keycode::B, Keycode::SELECT, Keycode::START,
+        (Keycode::RIGHT, Buttons::Right), (Keycode::LEFT, Buttons::Left),
+        (Keycode::UP, Buttons::Up), (Keycode::DOWN, Buttons::Down),
+        (Keycode::A, Buttons::A), (Keycode::B, Buttons::B),
+        (Keycode::SELECT, Buttons::Select), (Keycode::START, Buttons::Start),
     ];
     let keys: Vec<Keycode> = device_state.get_keys();
-    let mut gb_buttons = 0xFF;
+    let mut gb_buttons = Buttons::empty();
 
-    for (i, button) in buttons.iter().enumerate() {
-        if keys.contains(button) {
-            gb_buttons &= !(1 << i);
+    for (key, button) in buttons {
+        if keys.contains(&key) {
+            gb_buttons |= button;
         }
     }
 
-    gameboy.mmu.joypad.update_state(Buttons::from_bits(gb_buttons).unwrap());
+    gameboy.mmu.joypad.set_pressed(gb_buttons);
 }
 
 #[cfg(target_os = "linux")]
@@ -90,7 +92,7 @@ fn main() {
     }
     buffer.flip().unwrap();
     //panic!();
-    gameboy.mmu.joypad.update_state(Buttons::from_bits(0xFF).unwrap());
+    gameboy.mmu.joypad.set_pressed(Buttons::empty());
 
     let mut accumulator = Duration::new(0, 0);
     let target_frame_time = Duration::from_secs_f64(1.0 / 60.0);
@@ -124,7 +126,7 @@ fn main() {
         }
         buffer.flip().unwrap();
 
-        gameboy.mmu.apu.sample_buf = vec![];
+        gameboy.mmu.apu.sample_buf.clear();
 
         thread::sleep_ms(1000 / 60);
     }
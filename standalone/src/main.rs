@@ -4,7 +4,7 @@
 use error_iter::ErrorIter as _;
 use log::error;
 use std::collections::HashSet;
-use std::{env, fs, path::Path, fs::File};
+use std::{env, fs, path::{Path, PathBuf}, fs::File};
 use std::io::Write;
 use pixels::{Error, Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
@@ -13,8 +13,12 @@ use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 
-use viennetta_gb::hardware::{io::{cart::Cartridge, HEIGHT, WIDTH, LcdPixels, joypad::Buttons}, GameBoy};
+use viennetta_gb::hardware::{io::{cart::Cartridge, HEIGHT, WIDTH, joypad::Buttons, sgb::{SGB_WIDTH, SGB_HEIGHT}}, GameBoy};
+use viennetta_gb::hardware::io::apu::{AudioSource, Channels, DEFAULT_SAMPLE_RATE, vgm::Gd3Tags};
 use viennetta_gb::disasm::disasm;
+use viennetta_gb::link::tcp::TcpLink;
+use viennetta_gb::movie::Movie;
+use viennetta_gb::hardware::io::serial::printer::Printer;
 
 const PIXEL_SIZE: usize = 4;
 
@@ -23,26 +27,47 @@ enum Mode {
     TileDump,
 }
 
+enum MovieMode {
+    Recording(Movie, PathBuf),
+    /// The movie and the next frame to play.
+    Playing(Movie, usize),
+}
+
 /// Representation of the application state. In this example, a box will bounce around the screen.
 struct State {
     gameboy: GameBoy,
+    /// Where the VGM log goes, and the name of the game for its tags.
+    vgm_path: PathBuf,
+    game_name: String,
     mode: Mode,
     stepping: bool,
     breakpoints: HashSet<u16>,
     prev: u16,
+    movie: Option<MovieMode>,
 }
 
 impl State {
-    fn new(rom: &[u8]) -> Self {
+    fn new(rom: &[u8], game_name: String, sample_rate: u32, sgb: bool) -> Self {
         let mut breakpoints = HashSet::new();
         breakpoints.insert(0x150);
 
+        let mut gameboy = if sgb {
+            GameBoy::with_sgb(Cartridge::new(rom))
+        }
+        else {
+            GameBoy::new(Cartridge::new(rom))
+        };
+        gameboy.set_sample_rate(sample_rate);
+
         Self {
-            gameboy: GameBoy::new(Cartridge::new(rom)),
+            gameboy,
+            vgm_path: PathBuf::from("recording.vgm"),
+            game_name,
             mode: Mode::Normal,
             stepping: false,
             breakpoints,
             prev: 0,
+            movie: None,
         }
     }
 
@@ -61,22 +86,134 @@ impl State {
         else if input.key_pressed(VirtualKeyCode::F4) {
             self.stepping = true;
         }
+
+        // F5-F8 mute each channel, or solo it with shift held
+        let channels = [
+            (VirtualKeyCode::F5, Channels::Square1), (VirtualKeyCode::F6, Channels::Square2),
+            (VirtualKeyCode::F7, Channels::Wave), (VirtualKeyCode::F8, Channels::Noise),
+        ];
+        for (key, channel) in channels {
+            if input.key_pressed(key) {
+                let apu = &mut self.gameboy.mmu.apu;
+                if input.held_shift() {
+                    apu.solo.toggle(channel);
+                }
+                else {
+                    apu.muted.toggle(channel);
+                }
+            }
+        }
+
+        // F9 records the mix, shift+F9 records each channel to its own file
+        if input.key_pressed(VirtualKeyCode::F9) {
+            if self.gameboy.is_recording() {
+                self.stop_recording();
+            }
+            else if input.held_shift() {
+                self.start_channel_recording(Path::new("recording.wav"));
+            }
+            else {
+                self.start_recording(Path::new("recording.wav"));
+            }
+        }
+
+        // F10 logs APU writes to a VGM file, shift+F10 sets the loop point
+        if input.key_pressed(VirtualKeyCode::F10) {
+            if input.held_shift() {
+                self.gameboy.mark_vgm_loop();
+            }
+            else if self.gameboy.is_logging_vgm() {
+                self.stop_vgm_log();
+            }
+            else {
+                self.gameboy.start_vgm_log();
+                println!("Logging APU writes to {}", self.vgm_path.display());
+            }
+        }
     
         let buttons = [
-            VirtualKeyCode::Right, VirtualKeyCode::Left, VirtualKeyCode::Up, VirtualKeyCode::Down,
-            VirtualKeyCode::X, VirtualKeyCode::Z, VirtualKeyCode::RShift, VirtualKeyCode::Return,
+            (VirtualKeyCode::Right, Buttons::Right), (VirtualKeyCode::Left, Buttons::Left),
+            (VirtualKeyCode::Up, Buttons::Up), (VirtualKeyCode::Down, Buttons::Down),
+            (VirtualKeyCode::X, Buttons::A), (VirtualKeyCode::Z, Buttons::B),
+            (VirtualKeyCode::RShift, Buttons::Select), (VirtualKeyCode::Return, Buttons::Start),
         ];
-        let mut gb_buttons = 0xFF;
+        let mut gb_buttons = Buttons::empty();
     
-        for (i, button) in buttons.iter().enumerate() {
-            if input.key_held(*button) {
-                gb_buttons &= !(1 << i);
+        for (key, button) in buttons {
+            if input.key_held(key) {
+                gb_buttons |= button;
+            }
+        }
+
+        // the frame only goes into the movie, or moves playback on, once it's been run in full
+        if let Some(MovieMode::Playing(movie, frame)) = &self.movie {
+            match movie.frames.get(*frame) {
+                Some(&buttons) => gb_buttons = buttons,
+                None => {
+                    println!("Movie finished, back to the keyboard");
+                    self.movie = None;
+                },
             }
         }
 
-        self.gameboy.mmu.joypad.update_state(Buttons::from_bits(gb_buttons).unwrap());
+        self.gameboy.mmu.joypad.set_pressed(gb_buttons);
+    }
+
+    /// Moves the movie on by the frame that was just run.
+    fn advance_movie(&mut self) {
+        match &mut self.movie {
+            Some(MovieMode::Recording(movie, _)) => movie.frames.push(self.gameboy.mmu.joypad.pressed()),
+            Some(MovieMode::Playing(_, frame)) => *frame += 1,
+            None => {},
+        }
+    }
+
+    fn save_movie(&mut self) {
+        if let Some(MovieMode::Recording(movie, path)) = &self.movie {
+            match fs::write(path, movie.to_text()) {
+                Ok(()) => println!("Saved movie to {}", path.display()),
+                Err(err) => error!("Couldn't save movie to {}: {err}", path.display()),
+            }
+        }
     }
     
+    fn start_recording(&mut self, path: &Path) {
+        match self.gameboy.start_recording(path, AudioSource::Mixed) {
+            Ok(()) => println!("Recording audio to {}", path.display()),
+            Err(err) => error!("Couldn't record to {}: {err}", path.display()),
+        }
+    }
+
+    fn start_channel_recording(&mut self, path: &Path) {
+        for i in 0..4 {
+            let path = channel_recording_path(path, i);
+            match self.gameboy.start_recording(&path, AudioSource::Channel(i)) {
+                Ok(()) => println!("Recording channel {} to {}", i + 1, path.display()),
+                Err(err) => error!("Couldn't record to {}: {err}", path.display()),
+            }
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        match self.gameboy.stop_recording() {
+            Ok(()) => println!("Stopped recording audio"),
+            Err(err) => error!("Couldn't finish recording: {err}"),
+        }
+    }
+
+    fn stop_vgm_log(&mut self) {
+        let tags = Gd3Tags {
+            game_name: self.game_name.clone(),
+            system_name: "Nintendo Game Boy".to_string(),
+            ..Default::default()
+        };
+
+        match self.gameboy.stop_vgm_log(&self.vgm_path, &tags) {
+            Ok(()) => println!("Saved VGM log to {}", self.vgm_path.display()),
+            Err(err) => error!("Couldn't save VGM log to {}: {err}", self.vgm_path.display()),
+        }
+    }
+
     fn update_debug(&mut self) -> u8 {
         // are we stepping or at a breakpoint?
         // if self.gameboy.cpu.regs.pc == 0x2941 {
@@ -112,6 +249,7 @@ impl State {
             return self.gameboy.run_instruction();
         }
 
+        self.gameboy.mmu.sync_all();
         println!("{:04X}: {}", self.gameboy.cpu.regs.pc, disasm(self.gameboy.cpu.regs.pc, &self.gameboy.mmu));
         loop {
             let mut command = String::new();
@@ -202,9 +340,22 @@ impl State {
             }
         }
 
+        // stepping in the debugger only runs single instructions, which a movie can't hold
+        if total_cycles >= viennetta_gb::hardware::CYCLES_PER_FRAME {
+            self.advance_movie();
+        }
+
         if self.stepping {
             self.update_debug();
         }
+
+        // there's no audio output, so nothing else drains these
+        self.gameboy.mmu.sync_all();
+        let apu = &mut self.gameboy.mmu.apu;
+        apu.sample_buf.clear();
+        for buf in &mut apu.channel_bufs {
+            buf.clear();
+        }
     }
 
     fn draw(&mut self, frame: &mut [u8]) {
@@ -213,16 +364,34 @@ impl State {
             Mode::TileDump => self.gameboy.mmu.ppu.dump_tiles(),
         };
 
-        frame.copy_from_slice(&convert_gameboy_to_rgb565(screen));
+        match self.gameboy.mmu.sgb {
+            Some(ref sgb) if matches!(self.mode, Mode::Normal) => frame.copy_from_slice(&convert_gameboy_to_rgb565(&sgb.render(&screen))),
+            Some(_) => {
+                // the tile dump is only the size of the LCD, so put it in the middle of the frame
+                let mut framed = vec![0; SGB_WIDTH * SGB_HEIGHT];
+                let (left, top) = ((SGB_WIDTH - WIDTH) / 2, (SGB_HEIGHT - HEIGHT) / 2);
+                for (y, line) in screen.chunks_exact(WIDTH).enumerate() {
+                    framed[(top + y) * SGB_WIDTH + left..][..WIDTH].copy_from_slice(line);
+                }
+                frame.copy_from_slice(&convert_gameboy_to_rgb565(&framed));
+            },
+            None => frame.copy_from_slice(&convert_gameboy_to_rgb565(&screen)),
+        }
     }
 }
 
+/// `recording.wav` becomes `recording-ch1.wav` for the first channel, and so on.
+fn channel_recording_path(path: &Path, channel: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}-ch{}.wav", channel + 1))
+}
+
 fn col_conv(c: u16) -> u8 {
     ((c << 3) | (c >> 2)) as u8
 }
 
-pub fn convert_gameboy_to_rgb565(gameboy: LcdPixels) -> [u8; WIDTH * HEIGHT * PIXEL_SIZE] {
-    let mut result = [0; WIDTH * HEIGHT * PIXEL_SIZE];
+pub fn convert_gameboy_to_rgb565(gameboy: &[u16]) -> Vec<u8> {
+    let mut result = vec![0; gameboy.len() * PIXEL_SIZE];
 
     for (i, pixel) in gameboy.iter().enumerate() {
         let r = (pixel >> 10) & 0x1F;
@@ -242,8 +411,11 @@ fn main() -> Result<(), Error> {
     env_logger::init();
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
+    let args: Vec<String> = env::args().collect();
+    let sgb = args.iter().any(|arg| arg == "--sgb");
+    let (width, height) = if sgb { (SGB_WIDTH, SGB_HEIGHT) } else { (WIDTH, HEIGHT) };
     let window = {
-        let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
+        let size = LogicalSize::new(width as f64, height as f64);
         WindowBuilder::new()
             .with_title("Vienetta")
             .with_inner_size(size)
@@ -255,13 +427,60 @@ fn main() -> Result<(), Error> {
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(WIDTH as u32, HEIGHT as u32, surface_texture)?
+        Pixels::new(width as u32, height as u32, surface_texture)?
     };
 
     dbg!(pixels.surface_texture_format());
-    let args: Vec<String> = env::args().collect();
     let rom = fs::read(&args[1]).expect(format!("{} is not a valid path\n", args[1]).as_str());
-    let mut world = State::new(&rom);
+    let sample_rate = match args.iter().position(|arg| arg == "--sample-rate") {
+        Some(i) => args.get(i + 1).and_then(|rate| rate.parse().ok()).expect("--sample-rate needs a rate in Hz"),
+        None => DEFAULT_SAMPLE_RATE,
+    };
+    let game_name = Path::new(&args[1]).file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let mut world = State::new(&rom, game_name, sample_rate, sgb);
+
+    // movies start from power on, so these replace the Game Boy before anything else is set up
+    let movie_args = args.iter().any(|arg| arg == "--record-movie" || arg == "--play-movie");
+    assert!(!(sgb && movie_args), "movies can't be recorded or played back on the SGB");
+    if let Some(i) = args.iter().position(|arg| arg == "--record-movie") {
+        let path = PathBuf::from(args.get(i + 1).expect("--record-movie needs a path"));
+        let movie = Movie::new(&rom, world.gameboy.mmu.model);
+        world.movie = Some(MovieMode::Recording(movie, path));
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--play-movie") {
+        let path = args.get(i + 1).expect("--play-movie needs a path");
+        let text = fs::read_to_string(path).unwrap_or_else(|err| panic!("couldn't read {path}: {err}"));
+        let movie = Movie::parse(&text).unwrap_or_else(|err| panic!("{path} isn't a valid movie: {err}"));
+        world.gameboy = movie.start(&rom).unwrap_or_else(|err| panic!("couldn't play {path}: {err}"));
+        world.gameboy.set_sample_rate(sample_rate);
+        world.movie = Some(MovieMode::Playing(movie, 0));
+    }
+
+    if let Some(i) = args.iter().position(|arg| arg == "--record") {
+        world.start_recording(Path::new(args.get(i + 1).expect("--record needs a path")));
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--record-channels") {
+        world.start_channel_recording(Path::new(args.get(i + 1).expect("--record-channels needs a path")));
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--vgm") {
+        world.vgm_path = PathBuf::from(args.get(i + 1).expect("--vgm needs a path"));
+        world.gameboy.start_vgm_log();
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--printer") {
+        let directory = args.get(i + 1).expect("--printer needs a directory to save prints to");
+        world.gameboy.connect_serial(Box::new(Printer::new(directory)));
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--link-host") {
+        let addr = args.get(i + 1).expect("--link-host needs an address to listen on, like 0.0.0.0:5738");
+        println!("Waiting for the other player to connect on {addr}");
+        let link = TcpLink::host(addr.as_str()).expect("couldn't host the link cable");
+        world.gameboy.connect_serial(Box::new(link));
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--link-connect") {
+        let addr = args.get(i + 1).expect("--link-connect needs an address, like 192.168.0.2:5738");
+        let link = TcpLink::connect(addr.as_str()).expect("couldn't connect the link cable");
+        world.gameboy.connect_serial(Box::new(link));
+    }
 
     event_loop.run(move |event, _, control_flow| {
         // Draw the current frame
@@ -278,6 +497,13 @@ fn main() -> Result<(), Error> {
         if input.update(&event) {
             // Close events
             if input.key_pressed(VirtualKeyCode::Escape) || input.close_requested() {
+                if world.gameboy.is_recording() {
+                    world.stop_recording();
+                }
+                if world.gameboy.is_logging_vgm() {
+                    world.stop_vgm_log();
+                }
+                world.save_movie();
                 *control_flow = ControlFlow::Exit;
                 return;
             }
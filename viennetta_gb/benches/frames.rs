@@ -0,0 +1,27 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use viennetta_gb::hardware::{io::cart::Cartridge, GameBoy, Model};
+
+// Frames per second of a few test ROMs, which between them keep the CPU, PPU and timer busy. Each
+// one keeps running from where the last iteration left off, so most of it is past the boot ROM.
+
+const ROMS: [(&str, &[u8], Model); 3] = [
+    ("cpu_instrs", include_bytes!("../../cpu_instrs/cpu_instrs.gb"), Model::Cgb),
+    ("dmg-acid2", include_bytes!("../../dmg-acid2.gb"), Model::Dmg),
+    ("cgb-acid2", include_bytes!("../../cgb-acid2.gbc"), Model::Cgb),
+];
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frames");
+    // one element per frame, so criterion reports frames per second
+    group.throughput(Throughput::Elements(1));
+
+    for (name, rom, model) in ROMS {
+        let mut gameboy = GameBoy::with_model(Cartridge::new(rom), model);
+        group.bench_function(name, |b| b.iter(|| black_box(gameboy.run_frame())));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, frames);
+criterion_main!(benches);
@@ -0,0 +1,264 @@
+use std::fmt;
+
+use crate::hardware::{GameBoy, Model, io::cart::Cartridge};
+
+// GBS files are music ripped out of games: the sound driver and its data, plus a header saying
+// where to load it and which routines to call. To play one, we build a ROM around the data with a
+// little driver of our own that sets up the hardware, calls init, then calls play from an interrupt.
+
+const HEADER_LEN: usize = 0x70;
+/// The lowest load address allowed, leaving room for our driver underneath.
+const MIN_LOAD_ADDRESS: u16 = 0x400;
+const DRIVER_ADDRESS: u16 = 0x200;
+const BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GbsHeader {
+    pub song_count: u8,
+    /// 1-based, like the rest of the song numbers users see.
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    /// If bit 2 is set play is called from the timer interrupt, otherwise from VBlank.
+    /// Bit 7 asks for CGB double speed.
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GbsError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLoadAddress(u16),
+    /// The init or play routine isn't in the loaded data.
+    BadRoutineAddress(&'static str, u16),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort => write!(f, "file is too short to be a GBS file"),
+            Self::BadMagic => write!(f, "file doesn't start with \"GBS\""),
+            Self::UnsupportedVersion(version) => write!(f, "GBS version {version} isn't supported"),
+            Self::BadLoadAddress(address) => write!(f, "load address {address:04X} isn't between {MIN_LOAD_ADDRESS:04X} and 7FFF"),
+            Self::BadRoutineAddress(routine, address) => write!(f, "{routine} address {address:04X} isn't in the loaded data"),
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+#[derive(Debug, Clone)]
+pub struct Gbs {
+    pub header: GbsHeader,
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn parse(file: &[u8]) -> Result<Self, GbsError> {
+        if file.len() < HEADER_LEN {
+            return Err(GbsError::TooShort);
+        }
+        if &file[0..3] != b"GBS" {
+            return Err(GbsError::BadMagic);
+        }
+        if file[3] != 1 {
+            return Err(GbsError::UnsupportedVersion(file[3]));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([file[offset], file[offset + 1]]);
+        let string = |offset: usize| {
+            let field = &file[offset..offset + 32];
+            let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..len]).into_owned()
+        };
+
+        let header = GbsHeader {
+            song_count: file[4],
+            first_song: file[5],
+            load_address: word(6),
+            init_address: word(8),
+            play_address: word(0xA),
+            stack_pointer: word(0xC),
+            timer_modulo: file[0xE],
+            timer_control: file[0xF],
+            title: string(0x10),
+            author: string(0x30),
+            copyright: string(0x50),
+        };
+
+        if !(MIN_LOAD_ADDRESS..0x8000).contains(&header.load_address) {
+            return Err(GbsError::BadLoadAddress(header.load_address));
+        }
+
+        // the routines are called from the driver, so they have to be in the first 32 KiB
+        let data_end = (header.load_address as usize + file.len() - HEADER_LEN).min(0x8000);
+        for (routine, address) in [("init", header.init_address), ("play", header.play_address)] {
+            if !(header.load_address as usize..data_end).contains(&(address as usize)) {
+                return Err(GbsError::BadRoutineAddress(routine, address));
+            }
+        }
+
+        Ok(Self {
+            header,
+            data: file[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    /// Builds a Game Boy that plays `song`, counting from 0. The boot ROM is skipped, so it starts
+    /// straight into the song, with its audio coming out of `mmu.apu` as usual.
+    pub fn load_song(&self, song: u8, model: Model) -> GameBoy {
+        let mut gameboy = GameBoy::with_model(Cartridge::new(&self.build_rom(song, model)), model);
+        gameboy.mmu.write_memory(0xFF50, 1);
+        gameboy.cpu.regs.pc = DRIVER_ADDRESS;
+        gameboy.cpu.regs.sp = 0xFFFE;
+
+        gameboy
+    }
+
+    fn build_rom(&self, song: u8, model: Model) -> Vec<u8> {
+        let header = &self.header;
+        let load_address = header.load_address as usize;
+
+        // MBC5 with 8 KiB of RAM, rounded up to a ROM size the header can describe
+        let len = (load_address + self.data.len()).next_power_of_two().max(2 * BANK_SIZE);
+        let mut rom = vec![0; len];
+        rom[0x147] = 0x1B;
+        rom[0x148] = (len / (2 * BANK_SIZE)).trailing_zeros() as u8;
+        rom[0x149] = 0x02;
+        rom[load_address..load_address + self.data.len()].copy_from_slice(&self.data);
+
+        // RSTs are relative to the load address
+        for rst in (0x00..0x40).step_by(8) {
+            let [low, high] = (header.load_address + rst as u16).to_le_bytes();
+            rom[rst..rst + 3].copy_from_slice(&[0xC3, low, high]);     // jp load + rst
+        }
+
+        // interrupt vectors: VBlank and timer call play, the rest just return
+        let [play_low, play_high] = header.play_address.to_le_bytes();
+        for vector in [0x40, 0x48, 0x50, 0x58, 0x60] {
+            rom[vector] = 0xD9;                                         // reti
+        }
+        rom[0x40..0x44].copy_from_slice(&[0xCD, play_low, play_high, 0xD9]);   // call play; reti
+        rom[0x50..0x54].copy_from_slice(&[0xCD, play_low, play_high, 0xD9]);
+
+        let use_timer = header.timer_control & 0x04 != 0;
+        let interrupt = if use_timer { 0x04 } else { 0x01 };
+        let [sp_low, sp_high] = header.stack_pointer.to_le_bytes();
+        let [init_low, init_high] = header.init_address.to_le_bytes();
+
+        let mut driver = vec![
+            0xF3,                           // di
+            0x31, sp_low, sp_high,          // ld sp, stack pointer
+        ];
+        if header.timer_control & 0x80 != 0 && model == Model::Cgb {
+            driver.extend([
+                0x3E, 0x01, 0xE0, 0x4D,     // ld a, 1; ldh [KEY1], a
+                0x10, 0x00,                 // stop
+            ]);
+        }
+        driver.extend([
+            0x3E, 0x80, 0xE0, 0x26,         // ld a, $80; ldh [NR52], a
+            0x3E, 0x77, 0xE0, 0x24,         // ld a, $77; ldh [NR50], a
+            0x3E, 0xFF, 0xE0, 0x25,         // ld a, $FF; ldh [NR51], a
+            0x3E, header.timer_modulo, 0xE0, 0x06,         // ld a, modulo; ldh [TMA], a
+            0x3E, header.timer_control & 0x07, 0xE0, 0x07, // ld a, control; ldh [TAC], a
+            0x3E, 0x80, 0xE0, 0x40,         // ld a, $80; ldh [LCDC], a - VBlank needs the LCD on
+            0x3E, interrupt, 0xE0, 0xFF,    // ld a, interrupt; ldh [IE], a
+            0x3E, song,                     // ld a, song
+            0xCD, init_low, init_high,      // call init
+            0xAF, 0xE0, 0x0F,               // xor a; ldh [IF], a
+            0xFB,                           // ei
+            0x76,                           // .loop: halt
+            0x00,                           // nop
+            0x18, 0xFC,                     // jr .loop
+        ]);
+
+        let driver_address = DRIVER_ADDRESS as usize;
+        rom[driver_address..driver_address + driver.len()].copy_from_slice(&driver);
+
+        rom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A GBS file loading `data` at `load`, with init and play at the given addresses.
+    fn gbs_file(load: u16, init: u16, play: u16, data: &[u8]) -> Vec<u8> {
+        let mut file = vec![0; HEADER_LEN];
+        file[0..4].copy_from_slice(b"GBS\x01");
+        file[4] = 1;
+        file[5] = 1;
+        file[6..8].copy_from_slice(&load.to_le_bytes());
+        file[8..0xA].copy_from_slice(&init.to_le_bytes());
+        file[0xA..0xC].copy_from_slice(&play.to_le_bytes());
+        file[0xC..0xE].copy_from_slice(&0xDFFFu16.to_le_bytes());
+        file[0x10..0x15].copy_from_slice(b"Title");
+        file.extend_from_slice(data);
+        file
+    }
+
+    /// Init stores the song number at C000, and play counts up at C001.
+    fn counter_driver() -> Vec<u8> {
+        vec![
+            0xEA, 0x00, 0xC0, 0xC9,         // init: ld [$C000], a; ret
+            0xFA, 0x01, 0xC0, 0x3C,         // play: ld a, [$C001]; inc a
+            0xEA, 0x01, 0xC0, 0xC9,         // ld [$C001], a; ret
+        ]
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let file = gbs_file(0x400, 0x400, 0x404, &counter_driver());
+        assert_eq!(Gbs::parse(&file[..HEADER_LEN - 1]).unwrap_err(), GbsError::TooShort);
+
+        let mut bad_magic = file.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(Gbs::parse(&bad_magic).unwrap_err(), GbsError::BadMagic);
+
+        let mut bad_version = file.clone();
+        bad_version[3] = 2;
+        assert_eq!(Gbs::parse(&bad_version).unwrap_err(), GbsError::UnsupportedVersion(2));
+
+        let header = Gbs::parse(&file).unwrap().header;
+        assert_eq!((header.load_address, header.play_address, header.title.as_str()), (0x400, 0x404, "Title"));
+    }
+
+    #[test]
+    fn rejects_addresses_outside_the_rom() {
+        let data = counter_driver();
+        assert_eq!(Gbs::parse(&gbs_file(0x100, 0x100, 0x104, &data)).unwrap_err(), GbsError::BadLoadAddress(0x100));
+        assert_eq!(Gbs::parse(&gbs_file(0x8000, 0x8000, 0x8004, &data)).unwrap_err(), GbsError::BadLoadAddress(0x8000));
+        assert_eq!(Gbs::parse(&gbs_file(0x400, 0x3FF, 0x404, &data)).unwrap_err(), GbsError::BadRoutineAddress("init", 0x3FF));
+        assert_eq!(Gbs::parse(&gbs_file(0x400, 0x400, 0x40C, &data)).unwrap_err(), GbsError::BadRoutineAddress("play", 0x40C));
+    }
+
+    #[test]
+    fn builds_the_rom_around_the_data() {
+        let gbs = Gbs::parse(&gbs_file(0x400, 0x400, 0x404, &counter_driver())).unwrap();
+        let rom = gbs.build_rom(3, Model::Dmg);
+
+        assert_eq!(&rom[0x400..0x40C], &counter_driver()[..]);
+        assert_eq!(&rom[0x08..0x0B], &[0xC3, 0x08, 0x04]);             // rst $08 jumps to load + 8
+        assert_eq!(&rom[0x40..0x44], &[0xCD, 0x04, 0x04, 0xD9]);       // VBlank calls play
+        assert_eq!(&rom[0x50..0x54], &[0xCD, 0x04, 0x04, 0xD9]);       // as does the timer
+        assert_eq!(rom[0x48], 0xD9);
+        assert_eq!(&rom[0x200..0x204], &[0xF3, 0x31, 0xFF, 0xDF]);     // the driver sets up the stack first
+
+        // and it runs: init gets the song number, and play is called once a frame
+        let mut gameboy = gbs.load_song(3, Model::Dmg);
+        for _ in 0..10 {
+            gameboy.run_frame();
+        }
+        assert_eq!(gameboy.mmu.read_memory(0xC000), 3);
+        assert!((9..=10).contains(&gameboy.mmu.read_memory(0xC001)));
+    }
+}
@@ -1,4 +1,6 @@
-use self::{cpu::CPU, io::{MMU, cart::Cartridge}};
+use std::{fs::File, io::Result, path::Path};
+
+use self::{cpu::CPU, io::{MMU, cart::Cartridge, serial::SerialDevice, infrared::IrPeer, sgb::Sgb, apu::{AudioSource, vgm::Gd3Tags, ring_buffer::{self, AudioConsumer}}}};
 
 pub mod io;
 pub mod cpu;
@@ -6,6 +8,14 @@ mod boot_rom;
 
 pub const CYCLES_PER_FRAME: u32 = 17556 * 4;
 
+/// Which console is being emulated. Hardware quirks that differ between the two are gated on this.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Dmg,
+    #[default]
+    Cgb,
+}
+
 #[derive(Debug)]
 pub struct GameBoy {
     pub cpu: cpu::CPU,
@@ -14,12 +24,25 @@ pub struct GameBoy {
 
 impl GameBoy {
     pub fn new(cart: Cartridge) -> Self {
+        Self::with_model(cart, Model::default())
+    }
+
+    pub fn with_model(cart: Cartridge, model: Model) -> Self {
         Self {
             cpu: CPU::default(),
-            mmu: MMU::new(cart),
+            mmu: MMU::with_model(cart, model),
         }
     }
 
+    /// A DMG plugged into a Super Game Boy, which only listens to commands from cartridges with the
+    /// SGB flag set in their header.
+    pub fn with_sgb(cart: Cartridge) -> Self {
+        let enabled = cart.read_rom(0x146) == 0x03 && cart.read_rom(0x14B) == 0x33;
+        let mut gameboy = Self::with_model(cart, Model::Dmg);
+        gameboy.mmu.sgb = Some(Sgb::new(enabled));
+        gameboy
+    }
+
     pub fn run_frame(&mut self) -> io::LcdPixels {
         let mut total_cycles = 0;
 
@@ -35,18 +58,155 @@ impl GameBoy {
         //println!("FRAME FRAME FRAMETY FRAME Y: {} X: {} CYCLES: {}", self.mmu.ppu.line_y, self.mmu.ppu.line_x, self.mmu.ppu.cycles_line);
         // println!("{}", self.mmu.apu.sample_buf.len());
         // self.mmu.apu.sample_buf = vec![];
+        self.mmu.sync_all();
+        self.mmu.get_frame()
+    }
+
+    /// Runs until at least `target` samples are queued up, rather than for a fixed number of cycles,
+    /// so the emulation speed follows the audio device.
+    pub fn run_audio_synced(&mut self, target: usize) -> io::LcdPixels {
+        // a full ring buffer takes no more, so waiting for more than it holds would never end
+        let target = self.mmu.apu.queue_capacity().map_or(target, |capacity| target.min(capacity));
+        while self.mmu.apu.queued_samples() < target {
+            self.run_instruction();
+            self.mmu.sync_apu();
+            // no sound is made while stopped, so come back later rather than wait for it
+            if self.cpu.is_stopped() {
+                break;
+            }
+        }
+
+        self.mmu.sync_all();
         self.mmu.get_frame()
     }
 
+    /// Runs one instruction. The other components are only run as far as they need to be, so call
+    /// `mmu.sync_all` before looking at them directly.
     #[inline]
     pub fn run_instruction(&mut self) -> u8 {
         let cycles = self.cpu.tick(&mut self.mmu);
-        self.mmu.run_cycles(cycles, self.cpu.double_speed);
+        // STOP stops the system clock too, and the CPU checks the joypad itself to wake up
+        if !self.cpu.is_stopped() {
+            self.mmu.run_cycles(cycles, self.cpu.double_speed);
+        }
 
         cycles
     }
 
+    /// Plugs `device` into the link port, handing back whatever was there before.
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        self.mmu.serial.connect(device)
+    }
+
+    /// Points the CGB's IR port at `peer`, handing back whatever it was pointed at before.
+    pub fn connect_infrared(&mut self, peer: Box<dyn IrPeer>) -> Box<dyn IrPeer> {
+        self.mmu.infrared.connect(peer)
+    }
+
+    /// Sends audio to a new ring buffer of `capacity` samples instead of `mmu.apu.sample_buf`.
+    /// The returned end can be read from on another thread.
+    pub fn audio_ring_buffer(&mut self, capacity: usize) -> AudioConsumer {
+        let (producer, consumer) = ring_buffer::ring_buffer(capacity);
+        self.mmu.apu.set_ring_buffer(Some(producer));
+        consumer
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.mmu.apu.sample_rate()
+    }
+
+    /// Sets how many stereo samples per second of emulated time end up in `mmu.apu.sample_buf`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mmu.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Starts recording `source` to a 16-bit WAV file at `path`. Several recordings can run at once.
+    pub fn start_recording(&mut self, path: impl AsRef<Path>, source: AudioSource) -> Result<()> {
+        self.mmu.apu.start_recording(File::create(path)?, source)
+    }
+
+    /// Stops every recording, finishing off the files.
+    pub fn stop_recording(&mut self) -> Result<()> {
+        self.mmu.apu.stop_recording()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.mmu.apu.is_recording()
+    }
+
+    /// Starts logging APU register writes, to be saved as a VGM file by `stop_vgm_log`.
+    pub fn start_vgm_log(&mut self) {
+        self.mmu.apu.start_vgm_log();
+    }
+
+    /// Marks where VGM players should loop back to once they reach the end of the log.
+    pub fn mark_vgm_loop(&mut self) {
+        self.mmu.apu.mark_vgm_loop();
+    }
+
+    /// Stops logging and writes the VGM file to `path`. Does nothing if there's no log running.
+    pub fn stop_vgm_log(&mut self, path: impl AsRef<Path>, tags: &Gd3Tags) -> Result<()> {
+        match self.mmu.apu.finish_vgm_log(tags) {
+            Some(vgm) => std::fs::write(path, vgm),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        self.mmu.apu.is_logging_vgm()
+    }
+
     pub fn get_save_data(&self) -> Option<&Vec<u8>> {
         self.mmu.cart.get_save_data()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::io::joypad::Buttons;
+
+    #[test]
+    fn audio_sync_stops_at_a_full_ring_buffer() {
+        let mut gameboy = GameBoy::new(Cartridge::new(&vec![0; 0x8000]));
+        let consumer = gameboy.audio_ring_buffer(65);
+        assert_eq!(consumer.capacity(), 64);
+
+        // nothing is reading, and more is asked for than fits
+        gameboy.run_audio_synced(1000);
+        assert_eq!(consumer.len(), 64);
+    }
+
+    #[test]
+    fn stop_freezes_the_clock_until_a_button_is_pressed() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10C].copy_from_slice(&[
+            0x3E, 0x80, 0xE0, 0x40,         // ld a, $80; ldh [LCDC], a
+            0x3E, 0x20, 0xE0, 0x00,         // ld a, $20; ldh [P1], a
+            0x10, 0x00,                     // stop
+            0x18, 0xFE,                     // .loop: jr .loop
+        ]);
+        let mut gameboy = GameBoy::with_model(Cartridge::new(&rom), Model::Dmg);
+        gameboy.mmu.write_memory(0xFF50, 1);
+        gameboy.cpu.regs.pc = 0x100;
+
+        let div_and_ly = |gameboy: &mut GameBoy| {
+            gameboy.run_frame();
+            (gameboy.mmu.read_memory(0xFF04), gameboy.mmu.read_memory(0xFF44))
+        };
+        let stopped = div_and_ly(&mut gameboy);
+        assert!(gameboy.cpu.is_stopped());
+        assert_eq!(stopped.0, 0);
+        assert_eq!(div_and_ly(&mut gameboy), stopped);
+
+        // only the d-pad is selected
+        gameboy.mmu.joypad.set_pressed(Buttons::A);
+        assert_eq!(div_and_ly(&mut gameboy), stopped);
+
+        gameboy.mmu.joypad.set_pressed(Buttons::Right);
+        for _ in 0..1000 {
+            gameboy.run_instruction();
+        }
+        assert!(!gameboy.cpu.is_stopped());
+        assert_ne!((gameboy.mmu.read_memory(0xFF04), gameboy.mmu.read_memory(0xFF44)), stopped);
+    }
+}
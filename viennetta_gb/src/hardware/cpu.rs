@@ -5,6 +5,7 @@ use registers::*;
 use dbg_hex::dbg_hex;
 
 use super::io::{Interrupts, MMU};
+use super::io::ppu::OamCorruption;
 
 macro_rules! unsupported_opcode {
     ( $( $opcode:expr )+, $( $pc:expr )+ ) => {
@@ -18,17 +19,31 @@ pub struct CPU {
     int_master_enable: bool,
     ei_last_instruction: bool,
     halt_mode: bool,
+    /// Set by STOP, and only cleared by a button being pressed.
+    stopped: bool,
     pub double_speed: bool,
     freeze_count: u16,
 }
 
 impl CPU {
+    /// Whether STOP has the CPU, and with it the rest of the system, waiting on a button press.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
     pub fn tick(&mut self, mmu: &mut MMU) -> u8 {
         if self.freeze_count != 0 {
             self.freeze_count -= 1;
             return 1;
         }
 
+        if self.stopped {
+            if !mmu.joypad.any_selected_pressed() {
+                return 1;
+            }
+            self.stopped = false;
+        }
+
         if self.ei_last_instruction {
             self.int_master_enable = true;
             self.ei_last_instruction = false;
@@ -67,6 +82,8 @@ impl CPU {
 
                 // TODO: could be more accurate cycle wise
                 // https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+                // SP is decremented on the second M-cycle, then PC is pushed
+                mmu.set_access_cycle(1);
                 self.push_to_stack(self.regs.pc, mmu);
                 self.regs.pc = handler;
                 //println!("going to {handler:02X}");
@@ -84,12 +101,13 @@ impl CPU {
     }
 
     pub fn handle_opcode(&mut self, mmu: &mut MMU) -> u8 {
-        let opcode = mmu.read_memory(self.regs.pc);
+        let opcode = mmu.fetch(self.regs.pc);
+        mmu.set_access_cycle(first_access_cycle(opcode));
         //println!("{:04X}", self.regs.pc);
         //println!("{:04X}: {:02X}{:02X}", self.regs.pc, opcode, mmu.read_memory(self.regs.pc + 1));
         let block = opcode >> 6;
         
-        let cycles_cb = CB_CYCLES[mmu.read_memory(self.regs.pc + 1) as usize];
+        let cycles_cb = CB_CYCLES[mmu.fetch(self.regs.pc + 1) as usize];
         let cycles_normal = CYCLES_NORMAL[opcode as usize];
         let cycles_cond_taken = CYCLES_COND_TAKEN[opcode as usize];
 
@@ -99,7 +117,7 @@ impl CPU {
         match block {
             0x0 => {
                 let r16 = (opcode >> 4) & 0x03;
-                let imm16 = (mmu.read_memory(self.regs.pc + 1) as u16) << 8 | mmu.read_memory(self.regs.pc) as u16;
+                let imm16 = (mmu.fetch(self.regs.pc + 1) as u16) << 8 | mmu.fetch(self.regs.pc) as u16;
                 if opcode == 0x00 {
                     // nop
                     return cycles_normal;
@@ -122,17 +140,19 @@ impl CPU {
                         0xA => {
                             // ld a, [r16mem]
                             let addr = self.regs.get_r16_mem(r16);
-                            self.regs.a = mmu.read_memory(addr);
+                            self.regs.a = if r16 >= 2 { mmu.read_memory_increasing(addr) } else { mmu.read_memory(addr) };
                             return cycles_normal;
                         },
 
                         0x3 => {
                             // inc r16
+                            mmu.oam_bug(self.regs.get_r16(r16), OamCorruption::Write);
                             self.regs.apply_r16(r16, |r| r + 1);
                             return cycles_normal;
                         },
                         0xB => {
                             // dec r16
+                            mmu.oam_bug(self.regs.get_r16(r16), OamCorruption::Write);
                             self.regs.apply_r16(r16, |r| r - 1);
                             return cycles_normal;
                         },
@@ -182,7 +202,7 @@ impl CPU {
                         },
                         0x6 => {
                             // ld r8, imm8
-                            self.regs.set_r8(r8, mmu.read_memory(self.regs.pc), mmu);
+                            self.regs.set_r8(r8, mmu.fetch(self.regs.pc), mmu);
                             self.regs.pc += 1;
                             return cycles_normal;
                         },
@@ -294,7 +314,7 @@ impl CPU {
         
                 if opcode == 0x18 {
                     // jr imm8
-                    let offset = mmu.read_memory(self.regs.pc) as i8 as i16 + 1;
+                    let offset = mmu.fetch(self.regs.pc) as i8 as i16 + 1;
                     self.regs.pc = (self.regs.pc as i16 + offset) as u16;
                     return cycles_normal;
                 }
@@ -303,7 +323,7 @@ impl CPU {
                     // jr cond, imm8
                     let condition = self.regs.condition((opcode & 0x18) >> 3);
                     if condition {
-                        let offset = mmu.read_memory(self.regs.pc) as i8 as i16 + 1;
+                        let offset = mmu.fetch(self.regs.pc) as i8 as i16 + 1;
                         self.regs.pc = (self.regs.pc as i16 + offset) as u16;
                         return cycles_cond_taken;
                     }
@@ -319,6 +339,11 @@ impl CPU {
                         self.double_speed = !self.double_speed;
                         self.freeze_count = 2050;
                     }
+                    else if !mmu.joypad.any_selected_pressed() {
+                        // sleeps until a button is pressed, resetting DIV
+                        mmu.write_memory(0xFF04, 0);
+                        self.stopped = true;
+                    }
                     return 1;
                 }
             },
@@ -326,6 +351,7 @@ impl CPU {
                 if opcode == 0x76 {
                     // TODO: halt bug + more accuracy
                     self.halt_mode = true;
+                    return cycles_normal;
                 }
         
                 let source_reg = opcode & 0x07;
@@ -420,8 +446,8 @@ impl CPU {
             },
             0x3 => {
                 //dbg!(opcode);
-                let imm8 = mmu.read_memory(self.regs.pc);
-                let imm16 = (mmu.read_memory(self.regs.pc + 1) as u16) << 8 | (mmu.read_memory(self.regs.pc) as u16);
+                let imm8 = mmu.fetch(self.regs.pc);
+                let imm16 = (mmu.fetch(self.regs.pc + 1) as u16) << 8 | (mmu.fetch(self.regs.pc) as u16);
                 let condition = self.regs.condition((opcode & 0x18) >> 3);
 
                 self.regs.pc += 1;
@@ -670,7 +696,7 @@ impl CPU {
                         return cycles_normal;
                     },
                     0xCB => {
-                        self.execute_cb_opcode(mmu.read_memory(self.regs.pc), mmu);
+                        self.execute_cb_opcode(mmu.fetch(self.regs.pc), mmu);
                         self.regs.pc += 1;
                         return cycles_cb;
                     },
@@ -774,30 +800,35 @@ impl CPU {
                     },
                     0x20 => {
                         // sla r8
+                        let value = self.regs.get_r8(reg, mmu);
                         self.regs.flags = Flags::default();
-                        if self.regs.get_r8(reg, mmu) & 0x80 == 0x80 {
+                        if value & 0x80 == 0x80 {
                             self.regs.flags |= Flags::Carry;
                         }
-                        self.regs.apply_r8(reg, mmu, |r| r << 1);
-                        if self.regs.get_r8(reg, mmu) == 0x00 {
+                        self.regs.set_r8(reg, value << 1, mmu);
+                        if value << 1 == 0x00 {
                             self.regs.flags |= Flags::Zero;
                         }
                     },
                     0x28 => {
                         // sra r8
+                        let value = self.regs.get_r8(reg, mmu);
+                        let shifted = ((value as i8) >> 1) as u8;
                         self.regs.flags = Flags::default();
-                        if self.regs.get_r8(reg, mmu) & 0x01 == 0x01 {
+                        if value & 0x01 == 0x01 {
                             self.regs.flags |= Flags::Carry;
                         }
-                        self.regs.apply_r8(reg, mmu, |r| ((r as i8) >> 1) as u8);
-                        if self.regs.get_r8(reg, mmu) == 0x00 {
+                        self.regs.set_r8(reg, shifted, mmu);
+                        if shifted == 0x00 {
                             self.regs.flags |= Flags::Zero;
                         }
                     },
                     0x30 => {
                         // swap r8
-                        self.regs.apply_r8(reg, mmu, |r| ((r & 0x0F) << 4) | (r >> 4));
-                        if self.regs.get_r8(reg, mmu) == 0 {
+                        let value = self.regs.get_r8(reg, mmu);
+                        let swapped = ((value & 0x0F) << 4) | (value >> 4);
+                        self.regs.set_r8(reg, swapped, mmu);
+                        if swapped == 0 {
                             self.regs.flags = Flags::Zero;
                         }
                         else {
@@ -806,12 +837,13 @@ impl CPU {
                     }
                     0x38 => {
                         // srl r8
+                        let value = self.regs.get_r8(reg, mmu);
                         self.regs.flags = Flags::default();
-                        if self.regs.get_r8(reg, mmu) & 0x01 == 0x01 {
+                        if value & 0x01 == 0x01 {
                             self.regs.flags |= Flags::Carry;
                         }
-                        self.regs.apply_r8(reg, mmu, |r| r >> 1);
-                        if self.regs.get_r8(reg, mmu) == 0x00 {
+                        self.regs.set_r8(reg, value >> 1, mmu);
+                        if value >> 1 == 0x00 {
                             self.regs.flags |= Flags::Zero;
                         }
                     },
@@ -843,13 +875,18 @@ impl CPU {
     }
 
     fn push_to_stack(&mut self, value: u16, mmu: &mut MMU) {
+        // the first SP decrement happens on its own M-cycle before either write
+        mmu.oam_bug(self.regs.sp, OamCorruption::Write);
         mmu.write_memory(self.regs.sp - 1, (value >> 8) as u8);
         mmu.write_memory(self.regs.sp - 2, (value & 0xFF) as u8);
         self.regs.sp -= 2;
     }
 
     fn pop_from_stack(&mut self, mmu: &mut MMU) -> u16 {
-        let value = ((mmu.read_memory(self.regs.sp + 1) as u16) << 8) | mmu.read_memory(self.regs.sp) as u16;
+        // both reads happen alongside an SP increment
+        let low = mmu.read_memory_increasing(self.regs.sp);
+        let high = mmu.read_memory_increasing(self.regs.sp.wrapping_add(1));
+        let value = ((high as u16) << 8) | low as u16;
         self.regs.sp += 2;
         value
     }
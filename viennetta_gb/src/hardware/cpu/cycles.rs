@@ -53,4 +53,15 @@ pub const CB_CYCLES: [u8; 256] = [
 	2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
 	2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
 	2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2
-];
\ No newline at end of file
+];
+/// The M-cycle an instruction's first memory access lands in, counting the opcode fetch as 0. Any
+/// accesses after it, and IDU changes to SP before a push, take an M-cycle each.
+pub fn first_access_cycle(opcode: u8) -> u8 {
+    match opcode {
+        // ld [hl], imm8, ldh, ret cc and the CB prefix all have a cycle before the access
+        0x36 | 0xE0 | 0xF0 | 0xC0 | 0xC8 | 0xD0 | 0xD8 | 0xCB => 2,
+        // ld [imm16], sp, ld a <-> [imm16], and call, after their 16-bit operand
+        0x08 | 0xEA | 0xFA | 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => 3,
+        _ => 1,
+    }
+}
@@ -64,7 +64,7 @@ pub struct Registers {
 }
 
 impl Registers {
-    pub fn get_r8(&self, reg: u8, mmu: &MMU) -> u8 {
+    pub fn get_r8(&self, reg: u8, mmu: &mut MMU) -> u8 {
         match reg {
             0 => self.b,
             1 => self.c,
@@ -209,7 +209,8 @@ impl Registers {
     }
 
     pub fn add_r8(&mut self, reg: u8, value: u8, mmu: &mut MMU, set_carry: bool) {
-        let result = self.get_r8(reg, mmu).overflowing_add(value);
+        let old = self.get_r8(reg, mmu);
+        let result = old.overflowing_add(value);
         
         if set_carry {
             self.flags = Flags::empty();
@@ -224,7 +225,7 @@ impl Registers {
         if result.1 && set_carry {
             self.flags |= Flags::Carry;
         }
-        if (((value & 0xF) + (self.get_r8(reg, mmu) & 0xF)) & 0x10) == 0x10 {
+        if (((value & 0xF) + (old & 0xF)) & 0x10) == 0x10 {
             self.flags |= Flags::HalfCarry;
         }
 
@@ -232,7 +233,8 @@ impl Registers {
     }
 
     pub fn sub_r8(&mut self, reg: u8, value: u8, mmu: &mut MMU, set_carry: bool)  {
-        let result = self.get_r8(reg, mmu).overflowing_sub(value);
+        let old = self.get_r8(reg, mmu);
+        let result = old.overflowing_sub(value);
 
         if set_carry {
             self.flags = Flags::Negative;
@@ -248,7 +250,7 @@ impl Registers {
         if result.1 && set_carry {
             self.flags |= Flags::Carry;
         }
-        if (value & 0xF) > (self.get_r8(reg, mmu) & 0xF) {
+        if (value & 0xF) > (old & 0xF) {
             self.flags |= Flags::HalfCarry;
         }
 
@@ -336,14 +338,14 @@ mod tests {
             pc: 0x0000,
         };
 
-        assert_eq!(regs.get_r8(0, &mmu), regs.b);
-        assert_eq!(regs.get_r8(1, &mmu), regs.c);
-        assert_eq!(regs.get_r8(2, &mmu), regs.d);
-        assert_eq!(regs.get_r8(3, &mmu), regs.e);
-        assert_eq!(regs.get_r8(4, &mmu), regs.h);
-        assert_eq!(regs.get_r8(5, &mmu), regs.l);
-        assert_eq!(regs.get_r8(6, &mmu), mmu.read_memory(0xC607 as u16));
-        assert_eq!(regs.get_r8(7, &mmu), regs.a);
+        assert_eq!(regs.get_r8(0, &mut mmu), regs.b);
+        assert_eq!(regs.get_r8(1, &mut mmu), regs.c);
+        assert_eq!(regs.get_r8(2, &mut mmu), regs.d);
+        assert_eq!(regs.get_r8(3, &mut mmu), regs.e);
+        assert_eq!(regs.get_r8(4, &mut mmu), regs.h);
+        assert_eq!(regs.get_r8(5, &mut mmu), regs.l);
+        assert_eq!(regs.get_r8(6, &mut mmu), mmu.read_memory(0xC607 as u16));
+        assert_eq!(regs.get_r8(7, &mut mmu), regs.a);
     }
 
     #[test]
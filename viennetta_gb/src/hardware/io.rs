@@ -2,19 +2,26 @@ pub mod joypad;
 pub mod ppu;
 pub mod cart;
 pub mod apu;
-mod serial;
+pub mod serial;
+pub mod infrared;
+pub mod sgb;
+mod scheduler;
 mod timer;
 
 use dbg_hex::dbg_hex;
 use bitflags::bitflags;
 use log::warn;
 pub use ppu::{WIDTH, HEIGHT, LcdPixels};
-use self::ppu::PPU;
+use self::ppu::{PPU, OamCorruption};
 use self::apu::APU;
 use self::serial::Serial;
+use self::infrared::Infrared;
+use self::sgb::{Sgb, SgbPixels};
+use self::scheduler::{Scheduler, Event, Component};
 use self::timer::Timer;
 use self::joypad::Joypad;
 use super::boot_rom::{DMG_BOOT_ROM, CGB_BOOT_ROM};
+use super::Model;
 use self::cart::Cartridge;
 
 pub const T_CYCLES_RATE: u32 = 4 * 1024 * 1024;
@@ -78,10 +85,14 @@ bitflags! {
 
 #[derive(Debug)]
 pub struct MMU {
+    pub model: Model,
     pub ppu: PPU,
     pub apu: APU,
     ram: RAM,
-    serial: Serial,
+    pub serial: Serial,
+    pub infrared: Infrared,
+    /// The Super Game Boy the cartridge is plugged into, if any.
+    pub sgb: Option<Sgb>,
     pub timer: Timer,
     pub cart: Cartridge,
     pub joypad: Joypad,
@@ -98,16 +109,26 @@ pub struct MMU {
     vram_dma_len: u8,
     pub speed_switch: u8,
     pub double_speed: bool,
+    scheduler: Scheduler,
+    /// The M-cycle of the current instruction its next memory access lands in.
+    access_cycle: u8,
 }
 
 impl MMU {
     pub fn new(cart: Cartridge) -> Self {
-        Self {
-            ppu: PPU::default(),
+        Self::with_model(cart, Model::default())
+    }
+
+    pub fn with_model(cart: Cartridge, model: Model) -> Self {
+        let mut mmu = Self {
+            model,
+            ppu: PPU::new(model),
             ram: RAM::default(),
-            apu: APU::default(),
-            serial: Serial::default(),
-            timer: Timer::default(),
+            apu: APU::new(model),
+            serial: Serial::new(model),
+            infrared: Infrared::default(),
+            sgb: None,
+            timer: Timer::new(model),
             joypad: Joypad::default(),
             cart,
             int_enable: Interrupts::empty(),
@@ -123,46 +144,261 @@ impl MMU {
             vram_dma_len: 0,
             speed_switch: 0,
             double_speed: false,
-        }
+            scheduler: Scheduler::new(),
+            access_cycle: 0,
+        };
+        mmu.sync_all();
+        mmu
     }
 }
 
 impl MMU {
+    /// Moves time on by `cycles` M-cycles. Components are only run when an event they scheduled is
+    /// due, so until `sync_all` they can be behind.
     pub fn run_cycles(&mut self, cycles: u8, double_speed: bool) {
-        for _ in 0..cycles {
-            if double_speed {
-                //println!("double speed, {cycles}, {}", cycles * 2);
-                self.int_flag |= self.timer.run_cycles(4);
-                self.int_flag |= self.ppu.run_cycles(2);
-                self.apu.run_cycles(2);
+        self.access_cycle = 0;
+        if double_speed != self.double_speed {
+            // everything so far ran at the old speed, but what's scheduled next has to be at the new one
+            self.sync_all();
+            self.double_speed = double_speed;
+            self.schedule_timer();
+            self.schedule_ppu();
+            self.schedule_frame_sequencer();
+        }
+
+        self.scheduler.advance(cycles as u64);
+        while let Some((event, at)) = self.scheduler.pop_due() {
+            match event {
+                Event::TimerReload => self.sync_timer(),
+                Event::FrameSequencer => {
+                    // the DIV-APU event comes before the APU runs that M-cycle
+                    self.sync_apu_to(at - 1);
+                    self.apu.tick_frame_sequencer();
+                    let period = if self.double_speed { 4096 } else { 2048 };
+                    self.scheduler.schedule(Event::FrameSequencer, at + period);
+                },
+                Event::Ppu => self.sync_ppu(),
+                Event::Serial => self.sync_serial(),
             }
-            else {
-                self.int_flag |= self.timer.run_cycles(4);
-                self.int_flag |= self.ppu.run_cycles(4);
-                self.apu.run_cycles(4);
+        }
+
+        if self.joypad.take_interrupt() {
+            self.int_flag |= Interrupts::Joypad;
+        }
+    }
+
+    /// T-cycles at single speed, or dots, in an M-cycle.
+    fn cycle_len(&self) -> u8 {
+        if self.double_speed { 2 } else { 4 }
+    }
+
+    /// Runs every component up to now.
+    pub fn sync_all(&mut self) {
+        self.sync_timer();
+        self.sync_serial();
+        self.sync_apu();
+        self.sync_ppu();
+        self.sync_infrared();
+        self.schedule_frame_sequencer();
+    }
+
+    /// Runs the timer up to now, and schedules the next timer interrupt.
+    fn sync_timer(&mut self) {
+        let now = self.scheduler.now();
+        let behind = self.scheduler.catch_up(Component::Timer, now);
+        if behind > 0 {
+            self.int_flag |= self.timer.run_cycles(behind as u32 * 4);
+        }
+        self.schedule_timer();
+    }
+
+    fn schedule_timer(&mut self) {
+        match self.timer.cycles_until_interrupt() {
+            Some(cycles) => self.scheduler.schedule(Event::TimerReload, self.scheduler.now() + cycles.div_ceil(4) as u64),
+            None => self.scheduler.cancel(Event::TimerReload),
+        }
+    }
+
+    /// Schedules the next falling edge of the DIV bit that steps the frame sequencer. The timer has
+    /// to be up to date.
+    fn schedule_frame_sequencer(&mut self) {
+        let period = if self.double_speed { 0x4000 } else { 0x2000 };
+        let cycles = period - self.timer.counter() as u32 % period;
+        self.scheduler.schedule(Event::FrameSequencer, self.scheduler.now() + cycles.div_ceil(4) as u64);
+    }
+
+    /// Runs the serial port up to now. Transfers are clocked by the timer's counter, so while one is
+    /// going on it's run every M-cycle.
+    fn sync_serial(&mut self) {
+        self.sync_timer();
+        let now = self.scheduler.now();
+        let behind = self.scheduler.catch_up(Component::Serial, now);
+
+        if self.serial.is_transferring() {
+            // work out what the counter was on each of the M-cycles being caught up on
+            let bit = self.serial.clock_bit();
+            let mut counter = self.timer.counter().wrapping_sub((behind as u16).wrapping_mul(4));
+            for _ in 0..behind {
+                let next = counter.wrapping_add(4);
+                let edge = (counter >> bit) & 1 == 1 && (next >> bit) & 1 == 0;
+                self.int_flag |= self.serial.run_cycle(self.cycle_len(), edge);
+                counter = next;
+            }
+        }
+        else {
+            self.serial.run_idle(behind as u32 * self.cycle_len() as u32);
+        }
+        self.schedule_serial();
+    }
+
+    fn schedule_serial(&mut self) {
+        if self.serial.is_transferring() {
+            self.scheduler.schedule(Event::Serial, self.scheduler.now() + 1);
+        }
+        else {
+            self.scheduler.cancel(Event::Serial);
+        }
+    }
+
+    /// Runs the APU up to now, so its samples are all there.
+    pub fn sync_apu(&mut self) {
+        self.sync_apu_to(self.scheduler.now());
+    }
+
+    fn sync_apu_to(&mut self, to: u64) {
+        let behind = self.scheduler.catch_up(Component::Apu, to);
+        if behind > 0 {
+            self.apu.run_cycles(behind as u32 * self.cycle_len() as u32);
+        }
+    }
+
+    /// Runs the PPU up to now, and schedules when it next might change mode or request an interrupt.
+    fn sync_ppu(&mut self) {
+        self.sync_ppu_to(self.scheduler.now());
+    }
+
+    fn sync_ppu_to(&mut self, to: u64) {
+        let behind = self.scheduler.catch_up(Component::Ppu, to);
+        if behind > 0 {
+            let interrupts = self.ppu.run_cycles(behind as u32 * self.cycle_len() as u32);
+            if let (Some(sgb), true) = (&mut self.sgb, interrupts.contains(Interrupts::VBlank)) {
+                sgb.vblank(&self.ppu);
             }
+            self.int_flag |= interrupts;
+        }
+        self.schedule_ppu();
+    }
 
-            // if let Some(addr) = self.dma_transfer_offset {
-            //     self.ppu.write_oam(addr & 0xFF, self.read_memory(addr));
-            //     self.dma_transfer_offset = Some(addr + 1);
-            //     if (addr + 1) & 0xA0 == 0xA0 {
-            //         self.dma_transfer_offset = None;
-            //     }
-            // }
+    fn schedule_ppu(&mut self) {
+        let dots = self.cycle_len() as u32;
+        // the PPU can be ahead of now, for an access later in the current instruction
+        let synced = self.scheduler.synced(Component::Ppu);
+        match self.ppu.cycles_until_event() {
+            Some(cycles) => self.scheduler.schedule(Event::Ppu, synced + cycles.div_ceil(dots) as u64),
+            None => self.scheduler.cancel(Event::Ppu),
         }
     }
 
+    fn sync_infrared(&mut self) {
+        let behind = self.scheduler.catch_up(Component::Infrared, self.scheduler.now());
+        self.infrared.run_cycles(behind as u32 * self.cycle_len() as u32);
+    }
+
     pub fn get_frame(&self) -> LcdPixels {
         self.ppu.get_frame()
     }
 
-    pub fn read_memory(&self, address: u16) -> u8 {
+    /// The frame as the Super Game Boy shows it, coloured and with the border around it.
+    pub fn get_sgb_frame(&self) -> Option<SgbPixels> {
+        self.sgb.as_ref().map(|sgb| sgb.render(&self.ppu.get_frame()))
+    }
+
+    /// The lazily run component `address` belongs to, if any.
+    fn component_at(address: u16) -> Option<Component> {
+        match address {
+            0x8000..=0x9FFF | 0xFE00..=0xFEFF | 0xFF40..=0xFF4C | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6C => Some(Component::Ppu),
+            0xFF04..=0xFF07 => Some(Component::Timer),
+            0xFF10..=0xFF3F | 0xFF76..=0xFF77 => Some(Component::Apu),
+            0xFF01..=0xFF02 => Some(Component::Serial),
+            0xFF56 => Some(Component::Infrared),
+            _ => None,
+        }
+    }
+
+    /// Sets the M-cycle of the current instruction its next memory access lands in, counting the
+    /// opcode fetch as 0. Each access after that is taken to be on the next M-cycle.
+    pub fn set_access_cycle(&mut self, cycle: u8) {
+        self.access_cycle = cycle;
+    }
+
+    /// When the next access lands, in M-cycles since power on.
+    fn access_time(&self) -> u64 {
+        self.scheduler.now() + self.access_cycle as u64
+    }
+
+    /// Catches up whatever `address` belongs to before the CPU accesses it. The PPU is run up to
+    /// the M-cycle the access lands in, for VRAM and OAM locking, while the rest are seen as they
+    /// were at the start of the instruction.
+    fn sync_for(&mut self, address: u16) {
+        match Self::component_at(address) {
+            Some(Component::Ppu) => self.sync_ppu_to(self.access_time()),
+            Some(Component::Timer) => self.sync_timer(),
+            Some(Component::Apu) => self.sync_apu(),
+            Some(Component::Serial) => self.sync_serial(),
+            Some(Component::Infrared) => self.sync_infrared(),
+            None => {},
+        }
+    }
+
+    /// Reschedules whatever `address` belongs to after the CPU writes to it, as that can move its next event.
+    fn reschedule_for(&mut self, address: u16) {
+        match Self::component_at(address) {
+            Some(Component::Ppu) => self.schedule_ppu(),
+            Some(Component::Timer) => self.schedule_timer(),
+            Some(Component::Serial) => self.schedule_serial(),
+            _ => {},
+        }
+    }
+
+    pub fn read_memory(&mut self, address: u16) -> u8 {
+        let value = self.fetch(address);
+        self.access_cycle += 1;
+        value
+    }
+
+    /// Reads `address` while the IDU increments or decrements it, like `ld a, [hl+]` and `pop` do,
+    /// which corrupts OAM differently to a plain read.
+    pub fn read_memory_increasing(&mut self, address: u16) -> u8 {
+        self.corrupt_oam(address, OamCorruption::ReadIncrease);
+        self.sync_for(address);
+        self.access_cycle += 1;
+        self.read(address)
+    }
+
+    /// Reads an opcode or operand. The CPU reads these before the instruction's own accesses, so
+    /// they don't take up an M-cycle of it.
+    pub fn fetch(&mut self, address: u16) -> u8 {
+        self.corrupt_oam(address, OamCorruption::Read);
+        self.sync_for(address);
+        self.read(address)
+    }
+
+    fn read(&self, address: u16) -> u8 {
         if self.boot_rom_enable == 0 {
-            if address < 0x100 {
-                return CGB_BOOT_ROM[address as usize];
-            }
-            else if address >= 0x200 && address < 0x8FF {
-                return CGB_BOOT_ROM[address as usize];                
+            match self.model {
+                Model::Dmg => {
+                    if address < 0x100 {
+                        return DMG_BOOT_ROM[address as usize];
+                    }
+                }
+                Model::Cgb => {
+                    if address < 0x100 {
+                        return CGB_BOOT_ROM[address as usize];
+                    }
+                    else if address >= 0x200 && address < 0x8FF {
+                        return CGB_BOOT_ROM[address as usize];
+                    }
+                }
             }
         }
 
@@ -174,7 +410,10 @@ impl MMU {
             0xE000..=0xFDFF => self.ram.read_wram(address - 0xE000),   // Echo RAM
             0xFE00..=0xFE9F => self.ppu.read_oam(address - 0xFE00),    // OAM
             0xFF80..=0xFFFE => self.ram.read_hram(address - 0xFF80),   // HRAM
-            0xFF00 => self.joypad.read(),                                       // Joypad
+            0xFF00 => match &self.sgb {                                         // Joypad
+                Some(sgb) => sgb.read_p1(self.joypad.read()),
+                None => self.joypad.read(),
+            },
             0xFF01 => self.serial.read_data(),                                  // Serial Data
             0xFF02 => self.serial.read_control(),                               // Serial Control
             0xFF04..=0xFF07 => self.timer.read_io(address),                 // Timer
@@ -191,23 +430,52 @@ impl MMU {
             0xFF53 => (self.vram_dma_dest >> 8) as u8,                          // VRAM DMA
             0xFF54 => (self.vram_dma_dest & 0xFF) as u8,                         // VRAM DMA
             0xFF55 => { warn!("TODO: proper VRAM DMA transfer"); 0xFF },        // VRAM DMA
-            0xFF56 => { warn!("TODO: IR port read"); 0x0 },                     // IR port
-            0xFF68..0xFF6C => self.ppu.read_io(address),                        // PPU
+            0xFF56 if self.model == Model::Cgb => self.infrared.read(),         // IR port
+            0xFF68..=0xFF6C => self.ppu.read_io(address),                       // PPU
             0xFF70 => self.ram.wram_bank,                                       // WRAM bank
             0xFF72 => self.ff72,                                                // FF72
             0xFF73 => self.ff73,                                                // FF73
             0xFF74 => self.ff74,                                                // FF74
             0xFF75 => self.ff75,                                                // FF75
+            0xFF76..=0xFF77 if self.model == Model::Cgb => self.apu.read_pcm(address), // PCM12, PCM34
             0xFFFF => self.int_enable.bits() as u8,                             // Interrupt Enable
             _ => 0xFF,
         }
     }
 
+    /// Same as `read_memory` but VRAM and OAM ignore the PPU's access restrictions, so the DMA
+    /// engines and debugging tools can read them in any mode.
+    pub fn debug_read_memory(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => self.ppu.debug_read_vram(address - 0x8000),
+            0xFE00..=0xFE9F => self.ppu.debug_read_oam(address - 0xFE00),
+            _ => self.read(address),
+        }
+    }
+
+    /// Reports an IDU increment or decrement of `address` so the DMG OAM bug can be applied. Reads
+    /// and writes through the MMU report themselves. Like an access, this takes up an M-cycle.
+    pub fn oam_bug(&mut self, address: u16, kind: OamCorruption) {
+        self.corrupt_oam(address, kind);
+        self.access_cycle += 1;
+    }
+
+    fn corrupt_oam(&mut self, address: u16, kind: OamCorruption) {
+        if (0xFE00..=0xFEFF).contains(&address) {
+            self.sync_ppu_to(self.access_time());
+            self.ppu.oam_bug(kind);
+        }
+    }
+
     pub fn write_memory(&mut self, address: u16, value: u8) {
         if address == 0x99B1 {
             //println!("wrote {value:02X} to 0x99B1");
         }
 
+        self.corrupt_oam(address, OamCorruption::Write);
+        self.sync_for(address);
+        self.access_cycle += 1;
+
         match address {
             0x0000..=0x7FFF => self.cart.write_rom(address, value),                     // ROM
             0x8000..=0x9FFF => self.ppu.write_vram(address - 0x8000, value),   // VRAM
@@ -216,16 +484,38 @@ impl MMU {
             0xE000..=0xFDFF => self.ram.write_wram(address - 0xE000, value),   // Echo RAM
             0xFE00..=0xFE9F => self.ppu.write_oam(address - 0xFE00, value),    // OAM
             0xFF80..=0xFFFE => self.ram.write_hram(address - 0xFF80, value),   // HRAM
-            0xFF00 => self.joypad.write(value),                                         // Joypad
+            0xFF00 => {                                                                 // Joypad
+                self.joypad.write(value);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(value);
+                }
+            },
             0xFF01 => self.serial.write_data(value),                                    // Serial Data
             0xFF02 => self.serial.write_control(value),                                 // Serial Control
-            0xFF04..=0xFF07 => self.timer.write_io(address, value),                // Timer
+            0xFF04..=0xFF07 => {                                                    // Timer
+                // resetting DIV is a falling edge too if the bit was set
+                if address == 0xFF04 {
+                    self.sync_apu();
+                    self.sync_serial();
+                }
+                let div_apu_bit = self.timer.div_apu_bit(self.double_speed);
+                let serial_bit = self.timer.counter_bit(self.serial.clock_bit());
+                self.timer.write_io(address, value);
+                if div_apu_bit && !self.timer.div_apu_bit(self.double_speed) {
+                    self.apu.tick_frame_sequencer();
+                }
+                if serial_bit && !self.timer.counter_bit(self.serial.clock_bit()) {
+                    self.int_flag |= self.serial.run_cycle(0, true);
+                }
+                self.schedule_frame_sequencer();
+            },
             0xFF10..=0xFF26 => self.apu.write_io(address, value),                       // APU
             0xFF30..=0xFF3F => self.apu.write_wave(address - 0xFF30, value),   // APU Wave Pattern
             0xFF4D => self.speed_switch = value & 0x01,                                 // speed switch
             0xFF46 => self.oam_dma(value),                                      // OAM DMA
             0xFF40..=0xFF4B => self.ppu.write_io(address, value),                       // PPU
             0xFF4F => self.ppu.write_io(address, value),                                // PPU
+            0xFF4C if self.boot_rom_enable == 0 => self.ppu.write_io(address, value),   // KEY0 - CGB/DMG mode select
             0xFF0F => self.int_flag = Interrupts::from_bits(value & 0x1F).unwrap(),     // Interrupt Enable
             0xFF50 => self.boot_rom_enable = value,                                     // Boot ROM Enable/Disable
             0xFF51 => self.vram_dma_source = (self.vram_dma_dest & 0xFF) | (value as u16) << 8, // VRAM DMA
@@ -233,7 +523,7 @@ impl MMU {
             0xFF53 => self.vram_dma_dest = (self.vram_dma_dest & 0xFF) | (value as u16) << 8,   // VRAM DMA
             0xFF54 => self.vram_dma_dest = (self.vram_dma_dest & 0xFF00) | (value as u16),      // VRAM DMA
             0xFF55 => { self.vram_dma_len = value & 0x7F; self.vram_dma() },                             // VRAM DMA
-            0xFF56 => warn!("TODO: IR port write"),                                     // IR port
+            0xFF56 if self.model == Model::Cgb => self.infrared.write(value),           // IR port
             0xFF68..=0xFF6C => self.ppu.write_io(address, value),                       // PPU
             0xFF70 => self.ram.wram_bank = if value & 0x7 == 0 { 1 } else { value & 0x7 },     // WRAM bank
             0xFF72 => self.ff72 = value,                                                // FF72
@@ -244,6 +534,8 @@ impl MMU {
                 .expect(format!("{:02X} is not a valid IE value", value & 0x1F).as_str()),   // Interrupt Enable
             _ => {},
         }
+
+        self.reschedule_for(address);
     }
 
     fn oam_dma(&mut self, address: u8) {
@@ -251,7 +543,7 @@ impl MMU {
         // self.dma_transfer_offset = Some((address as u16) << 8);
 
         for offset in 0..0xA0 {
-            self.ppu.write_oam(offset, self.read_memory(((address as u16) << 8) | offset));
+            self.ppu.dma_write_oam(offset, self.debug_read_memory(((address as u16) << 8) | offset));
         }
     }
 
@@ -262,11 +554,91 @@ impl MMU {
         let len = (self.vram_dma_len as u16 + 1) * 0x10;
 
         for i in 0..len {
-            self.write_memory(dest + i, self.read_memory(source + i));
+            self.ppu.dma_write_vram((dest + i - 0x8000) & 0x1FFF, self.debug_read_memory(source + i));
         }
 
         // dbg_hex!(source);
         // dbg_hex!(dest);
         // dbg_hex!(len);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::infrared::ScriptedIr;
+
+    #[test]
+    fn ppu_sees_the_cycle_an_access_lands_in() {
+        let mut mmu = MMU::with_model(Cartridge::new(&vec![0; 0x8000]), Model::Dmg);
+        mmu.write_memory(0x8000, 0x12);
+        mmu.run_cycles(1, false);
+        mmu.write_memory(0xFF40, 0x80);
+
+        // one M-cycle before mode 3 starts on the first line
+        mmu.run_cycles(19, false);
+        assert_eq!(mmu.read_memory(0x8000), 0x12);
+
+        // like `ld a, [imm16]`, which reads 3 M-cycles into the instruction, by when VRAM is locked
+        mmu.set_access_cycle(3);
+        assert_eq!(mmu.read_memory(0x8000), 0xFF);
+    }
+
+    #[test]
+    fn ppu_events_follow_a_speed_switch() {
+        let mut mmu = MMU::with_model(Cartridge::new(&vec![0; 0x8000]), Model::Cgb);
+        mmu.write_memory(0xFF40, 0x80);
+        mmu.int_flag = Interrupts::empty();
+
+        // up to 200 dots before V-Blank, which is the PPU's next event, at double speed
+        for _ in 0..(144 * 456 - 200) / 2 {
+            mmu.run_cycles(1, true);
+        }
+
+        // back at single speed, that's 50 M-cycles away
+        let mut cycles = 0;
+        while !mmu.int_flag.contains(Interrupts::VBlank) {
+            mmu.run_cycles(1, false);
+            cycles += 1;
+        }
+        assert_eq!(cycles, 50);
+    }
+
+    #[test]
+    fn oam_dma_reads_vram_during_mode_3() {
+        let mut mmu = MMU::with_model(Cartridge::new(&vec![0; 0x8000]), Model::Dmg);
+        mmu.write_memory(0x8000, 0x12);
+        mmu.write_memory(0xFF40, 0x80);
+        mmu.run_cycles(25, false);
+
+        assert_eq!(mmu.read_memory(0x8000), 0xFF);
+        mmu.write_memory(0xFF46, 0x80);
+        assert_eq!(mmu.ppu.debug_read_oam(0), 0x12);
+    }
+
+    #[test]
+    fn ir_port_on_cgb_only() {
+        for model in [Model::Cgb, Model::Dmg] {
+            let mut mmu = MMU::with_model(Cartridge::new(&vec![0; 0x8000]), model);
+            let peer = ScriptedIr::new(vec![(0, 1000)]);
+            mmu.infrared.connect(Box::new(peer.clone()));
+
+            // LED on, and the pulse clears bit 1
+            mmu.write_memory(0xFF56, 0xC1);
+            let on = mmu.read_memory(0xFF56);
+            for _ in 0..3 {
+                mmu.run_cycles(100, false);
+            }
+            mmu.write_memory(0xFF56, 0xC0);
+            let off = mmu.read_memory(0xFF56);
+
+            if model == Model::Cgb {
+                assert_eq!((on, off), (0xFD, 0xFE));
+                assert_eq!(peer.led_changes(), [(0, true), (1200, false)]);
+            }
+            else {
+                assert_eq!((on, off), (0xFF, 0xFF));
+                assert_eq!(peer.led_changes(), []);
+            }
+        }
+    }
+}
@@ -1,120 +1,420 @@
-use log::warn;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use bitflags::bitflags;
+use log::{error, warn};
 use super::T_CYCLES_RATE;
+use crate::hardware::Model;
 
 mod square_wave;
 mod custom_wave;
 mod white_noise;
+mod blip_buffer;
+mod high_pass;
+mod length_counter;
+mod envelope;
+pub mod wav;
+pub mod vgm;
+pub mod ring_buffer;
 use square_wave::SquareWave;
 use custom_wave::CustomWave;
 use white_noise::WhiteNoise;
+use blip_buffer::BlipBuffer;
+use high_pass::HighPass;
+use wav::WavWriter;
+use vgm::{Gd3Tags, VgmLogger};
+use ring_buffer::AudioProducer;
 
-pub const SAMPLE_RATE: u16 = 48000;
-const SAMPLING_TIMER_INTERVAL: u32 = T_CYCLES_RATE / SAMPLE_RATE as u32;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
 
 pub type SampleBuffer = Vec<i16>;
 
+/// Scales the mixer output, which is at most 4 channels * 8/8 master volume either side of 0, to 16-bit samples.
+const OUTPUT_SCALE: f64 = i16::MAX as f64 / 4.0;
+/// The most T-cycles a level is held for in one go, so the high-pass filter's decay stays smooth.
+const MAX_HOLD: u32 = 32;
+
 // TODO: vin - external audio from cart. not sure if any games actually did this
 
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct Channels: u8 {
+        const Square1 = 1 << 0;
+        const Square2 = 1 << 1;
+        const Wave    = 1 << 2;
+        const Noise   = 1 << 3;
+    }
+}
+
+/// What an audio recording captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioSource {
+    /// Everything in `sample_buf`, after muting and soloing.
+    Mixed,
+    /// One of `channel_bufs`, from 0 to 3.
+    Channel(usize),
+}
+
+/// Turns one stereo signal from the mixer into samples: through the high-pass filter, then resampled.
+#[derive(Debug, Default)]
+struct OutputStage {
+    resampler: BlipBuffer,
+    left_filter: HighPass,
+    right_filter: HighPass,
+    level: (i16, i16),
+}
+
+impl OutputStage {
+    fn new(model: Model, sample_rate: u32) -> Self {
+        Self {
+            resampler: BlipBuffer::new(T_CYCLES_RATE, sample_rate),
+            left_filter: HighPass::new(model),
+            right_filter: HighPass::new(model),
+            level: (0, 0),
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = BlipBuffer::new(T_CYCLES_RATE, sample_rate);
+        self.resampler.set_levels(self.level.0, self.level.1);
+    }
+
+    /// Outputs the previous `cycles` T-cycles to `out`, then switches to `input`.
+    fn run(&mut self, (left, right): (f64, f64), cycles: u32, out: &mut SampleBuffer) {
+        self.resampler.advance(cycles, out);
+
+        let left = self.left_filter.process(left, cycles) * OUTPUT_SCALE;
+        let right = self.right_filter.process(right, cycles) * OUTPUT_SCALE;
+        self.level = (left as i16, right as i16);
+        self.resampler.set_levels(self.level.0, self.level.1);
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct APU {
+    model: Model,
     left_vol: u8,
     right_vol: u8,
+    /// The VIN bits of NR50. Nothing drives VIN, but they still read back.
+    vin: u8,
     enable: bool,
     channel1: SquareWave,
     channel2: SquareWave,
     channel3: CustomWave,
     channel4: WhiteNoise,
     pub sample_buf: SampleBuffer,
-    sampling_timer: u8,
-    frame_sequencer_cycle: u16,
+    output: OutputStage,
+    /// Channels left out of `sample_buf`. Only changes what's heard, the channels still run as normal.
+    pub muted: Channels,
+    /// If any channels are soloed, only those make it into `sample_buf` and `muted` is ignored.
+    pub solo: Channels,
+    /// Each channel's output on its own, in the same format as `sample_buf` and regardless of
+    /// `muted` and `solo`. Only filled while enabled with `set_channel_taps`.
+    pub channel_bufs: [SampleBuffer; 4],
+    channel_outputs: Vec<OutputStage>,
+    recordings: Vec<(AudioSource, WavWriter<BufWriter<File>>)>,
+    /// Whether `channel_bufs` were only turned on for recording a channel, so should go off again after.
+    taps_for_recording: bool,
+    /// If set, `sample_buf` is emptied into this as it fills.
+    ring_buffer: Option<AudioProducer>,
+    /// The next step the frame sequencer will run.
     frame_sequencer_step: u8,
+    /// T-cycles run since power on, for timestamping register writes.
+    cycles: u64,
+    /// The last value written to each register from NR10 to NR51, to be able to replay the current
+    /// state at the start of a VGM log. Most of them can't be read back.
+    registers: [u8; 0x16],
+    vgm: Option<VgmLogger>,
 }
 
 impl APU {
-    pub fn run_cycles(&mut self, cycles: u8) {
-        for _ in 0..cycles {
-            self.run_cycle();
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            channel3: CustomWave::new(model),
+            output: OutputStage::new(model, DEFAULT_SAMPLE_RATE),
+            ..Default::default()
         }
     }
-    
-    pub fn run_cycle(&mut self) {
-        if !self.enable {
-            self.sampling_timer += 1;
-            if self.sampling_timer as u32 == SAMPLING_TIMER_INTERVAL {
-                self.sampling_timer = 0;
-                self.sample_buf.push(0);
-                self.sample_buf.push(0);
+
+    pub fn sample_rate(&self) -> u32 {
+        self.output.resampler.sample_rate()
+    }
+
+    /// Changes the rate `sample_buf` is filled at. Anything not yet output at the old rate is dropped.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.output.set_sample_rate(sample_rate);
+        for output in &mut self.channel_outputs {
+            output.set_sample_rate(sample_rate);
+        }
+    }
+
+    /// Starts or stops filling `channel_bufs`.
+    pub fn set_channel_taps(&mut self, enable: bool) {
+        self.taps_for_recording = false;
+        self.channel_outputs = if enable {
+            (0..4).map(|_| OutputStage::new(self.model, self.sample_rate())).collect()
+        }
+        else {
+            vec![]
+        };
+    }
+
+    /// Sends output to `producer` rather than leaving it in `sample_buf`.
+    pub fn set_ring_buffer(&mut self, producer: Option<AudioProducer>) {
+        self.ring_buffer = producer;
+    }
+
+    /// The most samples that can be queued, if they're going to a ring buffer.
+    pub fn queue_capacity(&self) -> Option<usize> {
+        self.ring_buffer.as_ref().map(AudioProducer::capacity)
+    }
+
+    /// Samples output but not yet taken by the frontend, whether from the ring buffer or `sample_buf`.
+    pub fn queued_samples(&self) -> usize {
+        match &self.ring_buffer {
+            Some(ring_buffer) => ring_buffer.len(),
+            None => self.sample_buf.len(),
+        }
+    }
+
+    /// Starts writing `source` to `file` as a WAV file, at the current sample rate.
+    /// Recording a channel turns on `channel_bufs`.
+    pub fn start_recording(&mut self, file: File, source: AudioSource) -> io::Result<()> {
+        if let AudioSource::Channel(_) = source {
+            if self.channel_outputs.is_empty() {
+                self.set_channel_taps(true);
+                self.taps_for_recording = true;
             }
-            return;
         }
 
-        self.frame_sequencer_cycle += 1;
-        if self.frame_sequencer_cycle == 8192 { // TODO: falling edge bit 5 of div. shouldn't make a difference though
-            self.frame_sequencer_cycle = 0;
-            self.frame_sequencer_step += 1;
-            if self.frame_sequencer_step == 8 {
-                self.frame_sequencer_step = 0;
+        let writer = WavWriter::new(BufWriter::new(file), self.sample_rate(), 2)?;
+        self.recordings.push((source, writer));
+        Ok(())
+    }
+
+    /// Finishes every recording in progress, even if one of them fails, returning the first error.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for (_, writer) in self.recordings.drain(..) {
+            let finished = writer.finish().map(drop);
+            result = result.and(finished);
+        }
+        self.stop_recording_taps();
+        result
+    }
+
+    /// Turns `channel_bufs` back off if they were only on for channel recordings that have all stopped.
+    fn stop_recording_taps(&mut self) {
+        let recording_channel = self.recordings.iter().any(|(source, _)| matches!(source, AudioSource::Channel(_)));
+        if self.taps_for_recording && !recording_channel {
+            self.set_channel_taps(false);
+            self.taps_for_recording = false;
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        !self.recordings.is_empty()
+    }
+
+    /// Starts logging register writes for a VGM file, beginning with writes that recreate the current state.
+    pub fn start_vgm_log(&mut self) {
+        let mut vgm = VgmLogger::new(self.cycles);
+
+        vgm.log_write(self.cycles, 0xFF26, if self.enable { 0x80 } else { 0 });
+        vgm.log_write(self.cycles, 0xFF1A, 0);
+        for (i, value) in self.channel3.wave_ram().into_iter().enumerate() {
+            vgm.log_write(self.cycles, 0xFF30 + i as u16, value);
+        }
+
+        let playing = [(0xFF14, self.channel1.enable), (0xFF19, self.channel2.enable), (0xFF1E, self.channel3.enable), (0xFF23, self.channel4.enable)];
+        for (i, &value) in self.registers.iter().enumerate() {
+            let address = 0xFF10 + i as u16;
+            if address == 0xFF15 || address == 0xFF1F {
+                continue;
             }
 
-            if self.frame_sequencer_step % 2 == 0 {
-                self.channel1.tick_length_timer();
-                self.channel2.tick_length_timer();
-                self.channel3.tick_length_timer();
-                self.channel4.tick_length_timer();
+            // retrigger the channels that are playing, but not the rest
+            let value = match playing.iter().find(|(control, _)| *control == address) {
+                Some((_, true)) => value | 0x80,
+                Some((_, false)) => value & 0x7F,
+                None => value,
+            };
+            vgm.log_write(self.cycles, address, value);
+        }
+
+        self.vgm = Some(vgm);
+    }
+
+    /// Sets the point VGM players loop back to, at the current time.
+    pub fn mark_vgm_loop(&mut self) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.mark_loop(self.cycles);
+        }
+    }
+
+    /// Stops logging and returns the VGM file, if a log was running.
+    pub fn finish_vgm_log(&mut self, tags: &Gd3Tags) -> Option<Vec<u8>> {
+        self.vgm.take().map(|vgm| vgm.finish(self.cycles, tags))
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    /// The channels that make it into `sample_buf`.
+    fn audible_channels(&self) -> Channels {
+        if self.solo.is_empty() {
+            !self.muted
+        }
+        else {
+            self.solo
+        }
+    }
+
+    pub fn run_cycles(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+
+        let recorded_from = (self.sample_buf.len(), self.channel_bufs.each_ref().map(Vec::len));
+
+        // the channels only change level when their timers step, so run between those steps
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let chunk = self.cycles_until_step().min(remaining).min(MAX_HOLD);
+            remaining -= chunk;
+
+            if self.enable {
+                self.channel1.run_cycles(chunk);
+                self.channel2.run_cycles(chunk);
+                self.channel3.run_cycles(chunk);
+                self.channel4.run_cycles(chunk);
+            }
+
+            let levels = self.channel_levels();
+            let audible = self.audible_channels();
+
+            let mut mix = (0.0, 0.0);
+            for (i, (left, right)) in levels.iter().enumerate() {
+                if audible.contains(Channels::from_bits_truncate(1 << i)) {
+                    mix.0 += left;
+                    mix.1 += right;
+                }
             }
+            self.output.run(mix, chunk, &mut self.sample_buf);
 
-            if self.frame_sequencer_step == 7 {
-                self.channel1.tick_volume_envelope();
-                self.channel2.tick_volume_envelope();
-                self.channel4.tick_volume_envelope();
+            for ((output, buf), level) in self.channel_outputs.iter_mut().zip(&mut self.channel_bufs).zip(levels) {
+                output.run(level, chunk, buf);
             }
+        }
+
+        if !self.recordings.is_empty() {
+            self.record(recorded_from);
+        }
 
-            if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
-                self.channel1.tick_freq_sweep();
+        if let Some(ring_buffer) = &mut self.ring_buffer {
+            if !self.sample_buf.is_empty() {
+                ring_buffer.push(&self.sample_buf);
+                self.sample_buf.clear();
             }
         }
+    }
+
+    /// T-cycles until any channel's output next steps.
+    fn cycles_until_step(&self) -> u32 {
+        if !self.enable {
+            return u32::MAX;
+        }
+
+        [
+            self.channel1.cycles_until_step(),
+            self.channel2.cycles_until_step(),
+            self.channel3.cycles_until_step(),
+            self.channel4.cycles_until_step(),
+        ].into_iter().flatten().min().unwrap_or(u32::MAX)
+    }
+
+    /// Called on the DIV-APU event: a falling edge of bit 4 of DIV (bit 5 in double speed).
+    pub fn tick_frame_sequencer(&mut self) {
+        if !self.enable {
+            return;
+        }
+
+        if self.frame_sequencer_step % 2 == 0 {
+            self.channel1.tick_length_timer();
+            self.channel2.tick_length_timer();
+            self.channel3.tick_length_timer();
+            self.channel4.tick_length_timer();
+        }
+
+        if self.frame_sequencer_step == 7 {
+            self.channel1.tick_volume_envelope();
+            self.channel2.tick_volume_envelope();
+            self.channel4.tick_volume_envelope();
+        }
+
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.channel1.tick_freq_sweep();
+        }
+
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    /// Writes out everything added to the sample buffers since they were `from` long.
+    fn record(&mut self, from: (usize, [usize; 4])) {
+        let sample_buf = &self.sample_buf;
+        let channel_bufs = &self.channel_bufs;
+
+        self.recordings.retain_mut(|(source, writer)| {
+            let samples = match *source {
+                AudioSource::Mixed => &sample_buf[from.0..],
+                AudioSource::Channel(i) => &channel_bufs[i][from.1[i]..],
+            };
 
-        self.channel1.run_cycle();
-        self.channel2.run_cycle();
-        self.channel3.run_cycle();
-        self.channel4.run_cycle();
-
-        self.sampling_timer += 1;
-        if self.sampling_timer as u32 == SAMPLING_TIMER_INTERVAL {
-            self.sampling_timer = 0;
-            let mut left: u16 = 0;
-            let mut right: u16 = 0;
-
-            // yes I know that I'm calling it twice but the compiler should hopefully optimise it out
-            // TODO: check that that actually happens
-            left += self.channel1.get_amplitude().0 as u16;
-            right += self.channel1.get_amplitude().1 as u16;
-            left += self.channel2.get_amplitude().0 as u16;
-            right += self.channel2.get_amplitude().1 as u16;
-            left += self.channel3.get_amplitude().0 as u16;
-            right += self.channel3.get_amplitude().1 as u16;
-            left += self.channel4.get_amplitude().0 as u16;
-            right += self.channel4.get_amplitude().1 as u16;
-
-            left *= self.left_vol as u16;
-            right *= self.right_vol as u16;
-            // left /= 3;
-            // right /= 3;
-            
-            // I don't understand why this works but it does
-            // formula copied off Azayaka
-            let left = (left as i16 - 128) * 30;
-            let right = (right as i16 - 128) * 30;
-            self.sample_buf.push(left);
-            self.sample_buf.push(right);
+            match writer.write_samples(samples) {
+                Ok(()) => true,
+                Err(err) => {
+                    error!("Stopped recording audio: {err}");
+                    false
+                }
+            }
+        });
+        self.stop_recording_taps();
+    }
+
+    /// The analog output of each channel into either side of the mixer, before the high-pass filter.
+    fn channel_levels(&self) -> [(f64, f64); 4] {
+        if !self.enable {
+            return [(0.0, 0.0); 4];
         }
+
+        let channels = [
+            (self.channel1.dac_enabled(), self.channel1.get_amplitude(), self.channel1.left_pan, self.channel1.right_pan),
+            (self.channel2.dac_enabled(), self.channel2.get_amplitude(), self.channel2.left_pan, self.channel2.right_pan),
+            (self.channel3.dac_enabled(), self.channel3.get_amplitude(), self.channel3.left_pan, self.channel3.right_pan),
+            (self.channel4.dac_enabled(), self.channel4.get_amplitude(), self.channel4.left_pan, self.channel4.right_pan),
+        ];
+        let left_vol = (self.left_vol + 1) as f64 / 8.0;
+        let right_vol = (self.right_vol + 1) as f64 / 8.0;
+
+        channels.map(|(dac_enabled, amplitude, left_pan, right_pan)| {
+            // a DAC that's off outputs nothing, but an enabled DAC on a disabled channel still outputs
+            // its value for 0, which the high-pass filter then has to remove
+            if !dac_enabled {
+                return (0.0, 0.0);
+            }
+
+            // each DAC maps 0 to 15 linearly onto 1 to -1
+            let analog = 1.0 - amplitude as f64 / 7.5;
+            let left = if left_pan { analog * left_vol } else { 0.0 };
+            let right = if right_pan { analog * right_vol } else { 0.0 };
+            (left, right)
+        })
     }
 
     pub fn read_io(&self, address: u16) -> u8 {
         match address {
             0xFF26 => self.read_control_reg(),                 // NR52 - master control
             0xFF25 => self.read_pan_reg(),                     // NR51 - panning
-            0xFF24 => self.right_vol | (self.left_vol << 4),        // NR50 - volume
+            0xFF24 => self.right_vol | (self.left_vol << 4) | self.vin,        // NR50 - volume
             0xFF10..=0xFF14 => self.channel1.read_io(address),
             0xFF16..=0xFF19 => self.channel2.read_io(address),
             0xFF1A..=0xFF1E => self.channel3.read_io(address),
@@ -124,23 +424,66 @@ impl APU {
     }
 
     pub fn write_io(&mut self, address: u16, value: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.log_write(self.cycles, address, value);
+        }
+
+        if !self.enable && address != 0xFF26 {
+            self.write_io_powered_off(address, value);
+            return;
+        }
+
+        if address != 0xFF26 {
+            self.registers[(address - 0xFF10) as usize] = value;
+        }
+
+        let clocks_length_next = self.frame_sequencer_step % 2 == 0;
+
         match address {
             0xFF26 => self.write_control_reg(value),                 // NR52 - master control
             0xFF25 => self.write_pan_reg(value),                     // NR51 - panning
             0xFF24 => self.write_vol_reg(value),         // NR50 - volume
-            0xFF10..=0xFF14 => self.channel1.write_io(address, value),
-            0xFF16..=0xFF19 => self.channel2.write_io(address, value),
-            0xFF1A..=0xFF1E => self.channel3.write_io(address, value),
-            0xFF20..=0xFF23 => self.channel4.write_io(address, value),
+            0xFF10..=0xFF14 => self.channel1.write_io(address, value, clocks_length_next),
+            0xFF16..=0xFF19 => self.channel2.write_io(address, value, clocks_length_next),
+            0xFF1A..=0xFF1E => self.channel3.write_io(address, value, clocks_length_next),
+            0xFF20..=0xFF23 => self.channel4.write_io(address, value, clocks_length_next),
             _ => warn!("{address} not valid APU io address")
         };
     }
 
+    /// While powered off, register writes are ignored. The DMG still lets the length counters be loaded.
+    fn write_io_powered_off(&mut self, address: u16, value: u8) {
+        if self.model != Model::Dmg {
+            return;
+        }
+
+        match address {
+            0xFF11 => self.channel1.write_length(value),
+            0xFF16 => self.channel2.write_length(value),
+            0xFF1B => self.channel3.write_length(value),
+            0xFF20 => self.channel4.write_length(value),
+            _ => {}
+        }
+    }
+
+    /// PCM12 and PCM34 on CGB, the digital output of each pair of channels.
+    pub fn read_pcm(&self, address: u16) -> u8 {
+        match address {
+            0xFF76 => self.channel1.get_amplitude() | (self.channel2.get_amplitude() << 4),
+            0xFF77 => self.channel3.get_amplitude() | (self.channel4.get_amplitude() << 4),
+            _ => { warn!("{address} not valid APU PCM address"); 0xFF }
+        }
+    }
+
     pub fn read_wave(&self, address: u16) -> u8 {
         self.channel3.read_wave(address)
     }
 
     pub fn write_wave(&mut self, address: u16, value: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.log_write(self.cycles, 0xFF30 + address, value);
+        }
+
         self.channel3.write_wave(address, value);
     }
 
@@ -168,6 +511,7 @@ impl APU {
 
     fn read_control_reg(&self) -> u8 {
         (if self.enable { 0x80 } else { 0 })
+            | 0x70
             | (if self.channel1.enable { 0x01 } else { 0 })
             | (if self.channel2.enable { 0x02 } else { 0 })
             | (if self.channel3.enable { 0x04 } else { 0 })
@@ -175,17 +519,79 @@ impl APU {
     }
 
     fn write_control_reg(&mut self, value: u8)  {
-        self.enable = value & 0x80 == 0x80;
-        if !self.enable {
-            self.channel1.enable = false;
-            self.channel2.enable = false;
-            self.channel3.enable = false;
-            self.channel4.enable = false;
+        let enable = value & 0x80 == 0x80;
+
+        if self.enable && !enable {
+            // powering off clears NR10-NR51
+            self.channel1.power_off(self.model);
+            self.channel2.power_off(self.model);
+            self.channel3.power_off(self.model);
+            self.channel4.power_off(self.model);
+            self.write_vol_reg(0);
+            self.registers = [0; 0x16];
         }
+        else if !self.enable && enable {
+            self.frame_sequencer_step = 0;
+        }
+
+        self.enable = enable;
     }
 
     fn write_vol_reg(&mut self, value: u8) {
         self.right_vol = value & 0x7;
         self.left_vol = (value >> 4) & 0x7;
+        self.vin = value & 0x88;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_recordings_turn_taps_off_after() {
+        let path = std::env::temp_dir().join(format!("viennetta-apu-test-{}.wav", std::process::id()));
+        let mut apu = APU::new(Model::Cgb);
+
+        apu.start_recording(File::create(&path).unwrap(), AudioSource::Channel(2)).unwrap();
+        apu.run_cycles(4);
+        assert!(!apu.channel_outputs.is_empty());
+        apu.stop_recording().unwrap();
+        assert!(apu.channel_outputs.is_empty());
+
+        // but taps the frontend turned on itself stay on
+        apu.set_channel_taps(true);
+        apu.start_recording(File::create(&path).unwrap(), AudioSource::Channel(2)).unwrap();
+        apu.stop_recording().unwrap();
+        assert!(!apu.channel_outputs.is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn batched_cycles_match_single_cycles() {
+        let setup = || {
+            let mut apu = APU::new(Model::Cgb);
+            for address in 0..16 {
+                apu.write_wave(address, (address as u8).wrapping_mul(0x1D));
+            }
+            for (address, value) in [(0xFF26, 0x80), (0xFF11, 0x80), (0xFF12, 0xF0), (0xFF13, 0x9C), (0xFF14, 0x87),
+                                     (0xFF21, 0xF0), (0xFF22, 0x21), (0xFF23, 0x80), (0xFF1A, 0x80), (0xFF1C, 0x20),
+                                     (0xFF1D, 0xF0), (0xFF1E, 0x87)] {
+                apu.write_io(address, value);
+            }
+            apu
+        };
+        let mut batched = setup();
+        let mut single = setup();
+
+        for batch in [1, 3, 100, 4096, 7, 20000] {
+            batched.run_cycles(batch);
+            for _ in 0..batch {
+                single.run_cycles(1);
+            }
+            assert_eq!(batched.read_pcm(0xFF76), single.read_pcm(0xFF76));
+            assert_eq!(batched.read_pcm(0xFF77), single.read_pcm(0xFF77));
+            assert_eq!(batched.sample_buf.len(), single.sample_buf.len());
+        }
+    }
+}
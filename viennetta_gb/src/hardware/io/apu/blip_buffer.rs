@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use super::DEFAULT_SAMPLE_RATE;
+use crate::hardware::io::T_CYCLES_RATE;
+
+// Band-limited step synthesis, in the same spirit as blargg's blip_buf. Rather than point
+// sampling the output, every change in level is added to the output as a band-limited step
+// at its exact fractional position, so square waves don't alias at any output rate.
+
+const PHASES: usize = 32;
+const TAPS: usize = 16;
+/// How much of the output Nyquist frequency the kernel lets through. The rest is transition band.
+const CUTOFF: f64 = 0.9;
+
+#[derive(Debug)]
+pub struct BlipBuffer {
+    clock_rate: u64,
+    sample_rate: u64,
+    kernel: Vec<[f64; TAPS]>,
+    /// Pending deltas for the next `TAPS` output samples, per stereo channel.
+    deltas: VecDeque<[f64; 2]>,
+    /// Time since the first pending output sample, in units of 1 / `clock_rate` samples.
+    position: u64,
+    levels: [f64; 2],
+    integrator: [f64; 2],
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> Self {
+        Self {
+            clock_rate: clock_rate as u64,
+            sample_rate: sample_rate as u64,
+            kernel: Self::build_kernel(),
+            deltas: VecDeque::from(vec![[0.0; 2]; TAPS]),
+            position: 0,
+            levels: [0.0; 2],
+            integrator: [0.0; 2],
+        }
+    }
+
+    /// Windowed sinc impulses, one per sub-sample phase, each normalised to sum to 1 so that
+    /// integrating them gives a step of exactly the right height.
+    fn build_kernel() -> Vec<[f64; TAPS]> {
+        (0..PHASES).map(|phase| {
+            let centre = (TAPS / 2 - 1) as f64 + phase as f64 / PHASES as f64;
+            let mut impulse = [0.0; TAPS];
+
+            for (i, tap) in impulse.iter_mut().enumerate() {
+                let t = i as f64 - centre;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * CUTOFF * t).sin() / (PI * CUTOFF * t) };
+                // Blackman window spanning the whole kernel
+                let w = 2.0 * PI * (t / TAPS as f64 + 0.5);
+                let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                *tap = sinc * window;
+            }
+
+            let sum: f64 = impulse.iter().sum();
+            impulse.map(|tap| tap / sum)
+        }).collect()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    /// Sets the output level of each stereo channel from the current point in time onwards.
+    pub fn set_levels(&mut self, left: i16, right: i16) {
+        let phase = (self.position * PHASES as u64 / self.clock_rate) as usize;
+        let kernel = &self.kernel[phase];
+
+        for (channel, level) in [left, right].into_iter().enumerate() {
+            let delta = level as f64 - self.levels[channel];
+            if delta == 0.0 {
+                continue;
+            }
+
+            self.levels[channel] = level as f64;
+            for (pending, tap) in self.deltas.iter_mut().zip(kernel) {
+                pending[channel] += delta * tap;
+            }
+        }
+    }
+
+    /// Moves time forward by `clocks` input clocks, pushing every output sample that can no longer
+    /// be affected by future level changes onto `out` as interleaved stereo.
+    pub fn advance(&mut self, clocks: u32, out: &mut Vec<i16>) {
+        self.position += clocks as u64 * self.sample_rate;
+
+        while self.position >= self.clock_rate {
+            self.position -= self.clock_rate;
+
+            let delta = self.deltas.pop_front().unwrap_or_default();
+            self.deltas.push_back([0.0; 2]);
+
+            for (channel, delta) in delta.into_iter().enumerate() {
+                self.integrator[channel] += delta;
+                out.push(self.integrator[channel].round().clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+            }
+        }
+    }
+}
+
+impl Default for BlipBuffer {
+    fn default() -> Self {
+        Self::new(T_CYCLES_RATE, DEFAULT_SAMPLE_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_settles_at_the_new_level() {
+        let mut blip = BlipBuffer::new(1_000_000, 50_000);
+        let mut out = Vec::new();
+        blip.set_levels(1000, -500);
+        blip.advance(1_000, &mut out);
+
+        // the step is smeared over the kernel, but never far past either level
+        assert_eq!(out.len(), 2 * 50);
+        for sample in out.chunks(2) {
+            assert!((-100..=1100).contains(&sample[0]), "{sample:?}");
+            assert!((-550..=50).contains(&sample[1]), "{sample:?}");
+        }
+        assert_eq!(&out[2 * TAPS..], &[1000, -500].repeat(50 - TAPS)[..]);
+    }
+
+    #[test]
+    fn produces_sample_rate_samples_per_second() {
+        let mut blip = BlipBuffer::default();
+        let mut out = Vec::new();
+
+        // in uneven chunks, as the APU calls it
+        let mut clocks = 0;
+        for chunk in [4, 12, 8, 24, 20].into_iter().cycle() {
+            if clocks + chunk > T_CYCLES_RATE {
+                blip.advance(T_CYCLES_RATE - clocks, &mut out);
+                break;
+            }
+            blip.advance(chunk, &mut out);
+            clocks += chunk;
+        }
+        assert_eq!(out.len(), 2 * DEFAULT_SAMPLE_RATE as usize);
+    }
+}
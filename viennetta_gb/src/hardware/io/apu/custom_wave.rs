@@ -1,61 +1,114 @@
 use log::warn;
+use crate::hardware::Model;
+use super::length_counter::LengthCounter;
+
+/// On trigger the first sample is fetched this many T-cycles later than the period alone would say.
+const TRIGGER_DELAY: u16 = 6;
 
 #[derive(Debug, Default)]
 pub struct CustomWave {
+    model: Model,
     pub enable: bool,
+    dac_enable: bool,
     pub right_pan: bool,
     pub left_pan: bool,
     wave: [u8; 16],
-    length_timer: u16,
-    initial_length_timer: u16,
+    length: LengthCounter<256>,
     volume: u8,
     frequency: u16,
-    length_timer_enabled: bool,
     frequency_timer: u16,
-    wave_position: u8
+    wave_position: u8,
+    /// The byte of wave RAM the current sample comes from, latched when it was fetched.
+    sample_buffer: u8,
+    /// T-cycles since wave RAM was last fetched from. The DMG only lets the CPU at wave RAM right as that happens.
+    cycles_since_fetch: u16,
 }
 
 impl CustomWave {
-    pub fn run_cycle(&mut self) {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            ..Default::default()
+        }
+    }
+
+    pub fn run_cycles(&mut self, mut cycles: u32) {
         if !self.enable {
             return;
         }
 
-        self.frequency_timer -= 1;
-        if self.frequency_timer == 0 {
+        while cycles >= self.frequency_timer as u32 {
+            cycles -= self.frequency_timer as u32;
             self.frequency_timer = (2048 - self.frequency) * 2;
             self.wave_position += 1;
             if self.wave_position > 31 {
                 self.wave_position = 0;
             }
 
+            self.sample_buffer = self.wave[self.wave_position as usize / 2];
+            self.cycles_since_fetch = 0;
         }
+        self.frequency_timer -= cycles as u16;
+        self.cycles_since_fetch = self.cycles_since_fetch.saturating_add(cycles.try_into().unwrap_or(u16::MAX));
+    }
+
+    /// T-cycles until the next sample is fetched, or `None` if it won't be.
+    pub fn cycles_until_step(&self) -> Option<u32> {
+        self.enable.then_some(self.frequency_timer as u32)
+    }
+
+    /// Unlike the other channels, the wave channel's DAC has its own bit in NR30.
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enable
     }
 
     pub fn trigger_event(&mut self) {
-        self.enable = true;
-        self.wave_position = 0;
-        if self.length_timer == 0 {
-            self.length_timer = 256;
+        // retriggering on DMG right as a byte is being fetched overwrites the start of wave RAM
+        if self.model == Model::Dmg && self.enable && self.frequency_timer <= 2 {
+            let byte = ((self.wave_position as usize + 1) % 32) / 2;
+            if byte < 4 {
+                self.wave[0] = self.wave[byte];
+            }
+            else {
+                let start = byte & !3;
+                self.wave.copy_within(start..start + 4, 0);
+            }
         }
-        self.frequency_timer = (2048 - self.frequency) * 2;
+
+        self.enable = self.dac_enable;
+        self.wave_position = 0;
+        self.frequency_timer = (2048 - self.frequency) * 2 + TRIGGER_DELAY;
     }
 
     pub fn tick_length_timer(&mut self) {
-        if self.length_timer_enabled {
-            self.length_timer -= 1;
-            if self.length_timer == 0 {
-                self.enable = false;
-            }
+        if self.length.tick() {
+            self.enable = false;
         }
     }
 
-    pub fn get_amplitude(&self) -> (u8, u8) {
+    /// Clears every register. Wave RAM is left alone, as is the length counter on DMG.
+    pub fn power_off(&mut self, model: Model) {
+        self.length.power_off(model);
+        *self = Self {
+            model,
+            wave: self.wave,
+            length: std::mem::take(&mut self.length),
+            ..Default::default()
+        };
+    }
+
+    /// Writes NR31, which the DMG allows while the APU is off.
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value as u16);
+    }
+
+    /// The digital value going into the DAC, from 0 to 15.
+    pub fn get_amplitude(&self) -> u8 {
         if !self.enable {
-            return (0, 0);
+            return 0;
         }
 
-        let mut sample = self.wave[self.wave_position as usize / 2];
+        let mut sample = self.sample_buffer;
         if self.wave_position % 2 == 0 {
             sample >>= 4;
         }
@@ -71,32 +124,30 @@ impl CustomWave {
         else {
             self.volume - 1
         };
-        sample >>= vol_shift;
-
-        let left = if self.left_pan { sample } else { 0 };
-        let right = if self.right_pan { sample } else { 0 };
-
-        (left, right)
+        sample >> vol_shift
     }
 
     pub fn read_io(&self, address: u16) -> u8 {
         match address & 0xF {
-            0xA => if self.enable { 0xFF } else { 0x7F },
+            0xA => if self.dac_enable { 0xFF } else { 0x7F },
             0xB => 0xFF,
             0xC => (self.volume << 5) | 0x9F,
             0xD => 0xFF,
-            0xE => if self.length_timer_enabled { 0xFF } else { 0xBF },
+            0xE => if self.length.enabled { 0xFF } else { 0xBF },
             _ => { warn!("{address} not valid APU io address"); 0xFF }
         }
     }
 
-    pub fn write_io(&mut self, address: u16, value: u8) {
+    /// `clocks_length_next` is whether the next frame sequencer step clocks the length counter.
+    pub fn write_io(&mut self, address: u16, value: u8, clocks_length_next: bool) {
         match address & 0xF {
-            0xA => self.enable = value & 0x80 == 0x80,
-            0xB => {
-                self.initial_length_timer = value as u16;
-                self.length_timer = 256 - self.initial_length_timer;
+            0xA => {
+                self.dac_enable = value & 0x80 == 0x80;
+                if !self.dac_enable {
+                    self.enable = false;
+                }
             },
+            0xB => self.write_length(value),
             0xC => {
                 self.volume = value >> 5;
             },
@@ -104,9 +155,13 @@ impl CustomWave {
                 self.frequency = (self.frequency & 0x700) | value as u16;
             },
             0xE => {
-                self.length_timer_enabled = value & 0x40 == 0x40;
                 self.frequency = ((value as u16 & 0x7) << 8) | (self.frequency & 0xFF);
-                if value & 0x80 == 0x80 {
+
+                let trigger = value & 0x80 == 0x80;
+                if self.length.write_control(value & 0x40 == 0x40, trigger, clocks_length_next) {
+                    self.enable = false;
+                }
+                if trigger {
                     self.trigger_event();
                 }
             },
@@ -114,11 +169,120 @@ impl CustomWave {
         };
     }
 
+    /// Which byte of wave RAM the CPU gets at when it accesses `address`. While the channel is playing
+    /// that's the byte being played instead, and on DMG only right as it's fetched.
+    fn wave_access(&self, address: u16) -> Option<usize> {
+        if !self.enable {
+            return Some(address as usize);
+        }
+
+        match self.model {
+            Model::Cgb => Some(self.wave_position as usize / 2),
+            Model::Dmg if self.cycles_since_fetch < 2 => Some(self.wave_position as usize / 2),
+            Model::Dmg => None,
+        }
+    }
+
     pub fn read_wave(&self, address: u16) -> u8 {
-        self.wave[address as usize]
+        match self.wave_access(address) {
+            Some(index) => self.wave[index],
+            None => 0xFF,
+        }
     }
 
     pub fn write_wave(&mut self, address: u16, value: u8) {
-        self.wave[address as usize] = value;
+        if let Some(index) = self.wave_access(address) {
+            self.wave[index] = value;
+        }
+    }
+
+    /// Wave RAM as it is, without going through the CPU's access rules.
+    pub fn wave_ram(&self) -> [u8; 16] {
+        self.wave
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 512 T-cycle period, so each byte of wave RAM plays for 1024 T-cycles.
+    const PERIOD: u32 = 512;
+
+    /// A channel triggered with every byte of wave RAM different.
+    fn playing(model: Model) -> CustomWave {
+        let mut channel = CustomWave::new(model);
+        for i in 0..16 {
+            channel.write_wave(i, i as u8 * 0x11);
+        }
+        channel.write_io(0xFF1A, 0x80, false);
+        channel.write_io(0xFF1D, 0x00, false);
+        channel.write_io(0xFF1E, 0x87, false);
+        channel
     }
-}
\ No newline at end of file
+
+    /// Runs until just after the sample at `position` is fetched.
+    fn run_to_position(channel: &mut CustomWave, position: u32) {
+        channel.run_cycles(PERIOD + TRIGGER_DELAY as u32 + (position - 1) * PERIOD);
+        assert_eq!(channel.wave_position as u32, position);
+    }
+
+    #[test]
+    fn dmg_retrigger_copies_the_first_byte() {
+        // the next fetch is of byte 2, which gets copied to byte 0
+        let mut channel = playing(Model::Dmg);
+        run_to_position(&mut channel, 4);
+        channel.run_cycles(PERIOD - 2);
+        channel.write_io(0xFF1E, 0x87, false);
+        assert_eq!(channel.wave_ram()[..4], [0x22, 0x11, 0x22, 0x33]);
+    }
+
+    #[test]
+    fn dmg_retrigger_copies_a_block() {
+        // the next fetch is of byte 5, so the block of four it's in gets copied to the start
+        let mut channel = playing(Model::Dmg);
+        run_to_position(&mut channel, 9);
+        channel.run_cycles(PERIOD - 2);
+        channel.write_io(0xFF1E, 0x87, false);
+        assert_eq!(channel.wave_ram()[..8], [0x44, 0x55, 0x66, 0x77, 0x44, 0x55, 0x66, 0x77]);
+    }
+
+    #[test]
+    fn retrigger_away_from_a_fetch_leaves_wave_ram() {
+        let mut dmg = playing(Model::Dmg);
+        run_to_position(&mut dmg, 9);
+        dmg.run_cycles(PERIOD - 3);
+        dmg.write_io(0xFF1E, 0x87, false);
+
+        let mut cgb = playing(Model::Cgb);
+        run_to_position(&mut cgb, 9);
+        cgb.run_cycles(PERIOD - 2);
+        cgb.write_io(0xFF1E, 0x87, false);
+
+        let untouched = playing(Model::Dmg).wave_ram();
+        assert_eq!((dmg.wave_ram(), cgb.wave_ram()), (untouched, untouched));
+    }
+
+    #[test]
+    fn cgb_accesses_the_playing_byte() {
+        let mut channel = playing(Model::Cgb);
+        run_to_position(&mut channel, 3);
+        channel.run_cycles(100);
+        assert_eq!(channel.read_wave(0x0A), 0x11);
+        channel.write_wave(0x0A, 0xAB);
+        assert_eq!(channel.wave_ram()[1], 0xAB);
+    }
+
+    #[test]
+    fn dmg_accesses_the_playing_byte_only_as_it_is_fetched() {
+        let mut channel = playing(Model::Dmg);
+        run_to_position(&mut channel, 3);
+        assert_eq!(channel.read_wave(0x0A), 0x11);
+        channel.run_cycles(1);
+        assert_eq!(channel.read_wave(0x0A), 0x11);
+
+        channel.run_cycles(1);
+        assert_eq!(channel.read_wave(0x0A), 0xFF);
+        channel.write_wave(0x0A, 0xAB);
+        assert_eq!(channel.wave_ram(), playing(Model::Dmg).wave_ram());
+    }
+}
@@ -0,0 +1,110 @@
+/// The volume envelope of the square and noise channels, controlled by NRx2.
+#[derive(Debug, Default)]
+pub struct Envelope {
+    initial_volume: u8,
+    is_increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+    /// Cleared once the volume hits 0 or 15, after which the envelope stops changing it.
+    running: bool,
+}
+
+impl Envelope {
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    /// The DAC is powered by the top five bits of NRx2, separately from the channel being enabled.
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.is_increase
+    }
+
+    pub fn read(&self) -> u8 {
+        (self.initial_volume << 4)
+            | if self.is_increase { 0x8 } else { 0 }
+            | self.period
+    }
+
+    /// Handles a write to NRx2. Writing while the channel plays changes the volume in odd ways
+    /// ("zombie mode"), which some sound drivers use to change volume without retriggering.
+    pub fn write(&mut self, value: u8, playing: bool) {
+        let was_increase = self.is_increase;
+        let old_period = self.period;
+
+        self.initial_volume = value >> 4;
+        self.is_increase = value & 0x8 == 0x8;
+        self.period = value & 0x7;
+
+        if playing {
+            // the volume is a 4-bit counter, so all of this wraps
+            if old_period == 0 && self.running {
+                self.volume += 1;
+            }
+            else if !was_increase {
+                self.volume += 2;
+            }
+            self.volume &= 0xF;
+
+            if was_increase != self.is_increase {
+                self.volume = 16u8.wrapping_sub(self.volume) & 0xF;
+            }
+        }
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.reload_value();
+        self.running = true;
+    }
+
+    /// A period of 0 still runs the timer, as if it were 8, it just never changes the volume.
+    fn reload_value(&self) -> u8 {
+        if self.period == 0 { 8 } else { self.period }
+    }
+
+    /// Clocked by the frame sequencer.
+    pub fn tick(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.reload_value();
+        if self.period == 0 || !self.running {
+            return;
+        }
+
+        if self.is_increase && self.volume < 0xF {
+            self.volume += 1;
+        }
+        else if !self.is_increase && self.volume > 0x0 {
+            self.volume -= 1;
+        }
+        else {
+            self.running = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zombie_mode_wraps() {
+        let mut envelope = Envelope::default();
+        envelope.write(0xF1, false);
+        envelope.trigger();
+
+        // 15 + 2 wraps round to 1, then switching to increasing flips it to 15
+        envelope.write(0xF9, true);
+        assert_eq!(envelope.volume(), 15);
+
+        // from 0 it goes up to 2, which flips to 14
+        envelope.write(0x01, false);
+        envelope.trigger();
+        envelope.write(0x09, true);
+        assert_eq!(envelope.volume(), 14);
+    }
+}
@@ -0,0 +1,40 @@
+use crate::hardware::Model;
+
+// The output of the mixer goes through a capacitor before reaching the amplifier, which strips
+// out any DC offset. Without it, turning a DAC on or off would shift the whole waveform.
+
+/// How much charge the capacitor keeps each T-cycle. The CGB's capacitor discharges faster.
+const DMG_CHARGE_FACTOR: f64 = 0.999958;
+const CGB_CHARGE_FACTOR: f64 = 0.998943;
+
+#[derive(Debug, Default)]
+pub struct HighPass {
+    charge_factor: f64,
+    capacitor: f64,
+}
+
+impl HighPass {
+    pub fn new(model: Model) -> Self {
+        let charge_factor = match model {
+            Model::Dmg => DMG_CHARGE_FACTOR,
+            Model::Cgb => CGB_CHARGE_FACTOR,
+        };
+
+        Self {
+            charge_factor,
+            capacitor: 0.0,
+        }
+    }
+
+    /// Filters `input`, which has been held for `cycles` T-cycles.
+    pub fn process(&mut self, input: f64, cycles: u32) -> f64 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor.powi(cycles as i32);
+        // with no input the capacitor would decay into subnormal floats, which are very slow to
+        // work with, long after it's too small to make a difference
+        if self.capacitor.abs() < f64::MIN_POSITIVE {
+            self.capacitor = 0.0;
+        }
+        output
+    }
+}
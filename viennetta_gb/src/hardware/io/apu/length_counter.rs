@@ -0,0 +1,137 @@
+use crate::hardware::Model;
+
+/// The length timer shared by every channel. `MAX` is 64, or 256 for the wave channel.
+#[derive(Debug, Default)]
+pub struct LengthCounter<const MAX: u16> {
+    pub enabled: bool,
+    counter: u16,
+}
+
+impl<const MAX: u16> LengthCounter<MAX> {
+    /// Loads the counter from the length bits of NRx1.
+    pub fn load(&mut self, value: u16) {
+        self.counter = MAX - value;
+    }
+
+    /// Clocked by the frame sequencer. Returns true when the counter runs out and the channel should be disabled.
+    pub fn tick(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    /// Handles the length enable and trigger bits of a write to NRx4. `clocks_next` is whether the next
+    /// frame sequencer step clocks length counters.
+    /// Returns true if the channel should be disabled.
+    pub fn write_control(&mut self, enable: bool, trigger: bool, clocks_next: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+
+        // enabling the counter during a step that doesn't clock it clocks it an extra time
+        let mut expired = false;
+        if !clocks_next && !was_enabled && enable && self.counter != 0 {
+            self.counter -= 1;
+            expired = self.counter == 0 && !trigger;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = MAX;
+            // and the same goes for the reload on trigger
+            if enable && !clocks_next {
+                self.counter -= 1;
+            }
+        }
+
+        expired
+    }
+
+    /// The DMG keeps the length counters through a power cycle, the CGB clears them.
+    pub fn power_off(&mut self, model: Model) {
+        self.enabled = false;
+        if model == Model::Cgb {
+            self.counter = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::io::apu::APU;
+
+    fn loaded(counter: u16) -> LengthCounter<64> {
+        let mut length = LengthCounter::default();
+        length.load(64 - counter);
+        length
+    }
+
+    #[test]
+    fn enabling_before_a_clocking_step() {
+        let mut length = loaded(10);
+        assert!(!length.write_control(true, false, true));
+        assert_eq!(length.counter, 10);
+    }
+
+    #[test]
+    fn enabling_before_a_non_clocking_step() {
+        let mut length = loaded(10);
+        assert!(!length.write_control(true, false, false));
+        assert_eq!(length.counter, 9);
+
+        // already enabled, so no extra clock
+        assert!(!length.write_control(true, false, false));
+        assert_eq!(length.counter, 9);
+
+        // the extra clock can run the counter out
+        let mut length = loaded(1);
+        assert!(length.write_control(true, false, false));
+        assert_eq!(length.counter, 0);
+    }
+
+    #[test]
+    fn trigger_at_zero_reloads() {
+        for (enable, clocks_next, counter) in [(true, true, 64), (false, false, 64), (true, false, 63)] {
+            let mut length = loaded(0);
+            assert!(!length.write_control(enable, true, clocks_next));
+            assert_eq!(length.counter, counter, "enable: {enable}, clocks_next: {clocks_next}");
+        }
+
+        // and a trigger that runs the counter out with the extra clock reloads it rather than disabling
+        let mut length = loaded(1);
+        assert!(!length.write_control(true, true, false));
+        assert_eq!(length.counter, 63);
+    }
+
+    #[test]
+    fn power_off_keeps_the_counter_on_dmg_only() {
+        for (model, counter) in [(Model::Dmg, 10), (Model::Cgb, 0)] {
+            let mut length = loaded(10);
+            length.enabled = true;
+            length.power_off(model);
+            assert!(!length.enabled);
+            assert_eq!(length.counter, counter, "{model:?}");
+        }
+    }
+
+    #[test]
+    fn length_writes_while_powered_off_on_dmg_only() {
+        for (model, disabled) in [(Model::Dmg, true), (Model::Cgb, false)] {
+            let mut apu = APU::new(model);
+            apu.write_io(0xFF26, 0x00);
+            apu.write_io(0xFF16, 63);
+            apu.write_io(0xFF17, 0xF0);
+            assert_eq!(apu.read_io(0xFF17), 0x00, "{model:?}");
+
+            // a length of 1 runs out on the first step, otherwise the trigger loads 64
+            apu.write_io(0xFF26, 0x80);
+            apu.write_io(0xFF17, 0xF0);
+            apu.write_io(0xFF19, 0xC0);
+            assert_eq!(apu.read_io(0xFF26) & 0x02, 0x02);
+            apu.tick_frame_sequencer();
+            assert_eq!(apu.read_io(0xFF26) & 0x02 == 0, disabled, "{model:?}");
+        }
+    }
+}
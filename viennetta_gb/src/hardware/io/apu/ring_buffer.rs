@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI16, AtomicUsize, Ordering};
+
+// A fixed size single-producer single-consumer queue of samples. The emulator thread pushes into
+// one end and the audio thread pulls from the other without either of them ever blocking or
+// allocating. Positions only ever increase and are wrapped on access, so full and empty can be told apart.
+
+#[derive(Debug)]
+struct Shared {
+    samples: Box<[AtomicI16]>,
+    /// Total samples ever written. Only the producer changes it.
+    write_pos: AtomicUsize,
+    /// Total samples ever read. Only the consumer changes it.
+    read_pos: AtomicUsize,
+    overruns: AtomicUsize,
+    underruns: AtomicUsize,
+}
+
+impl Shared {
+    fn len(&self) -> usize {
+        self.write_pos.load(Ordering::Acquire) - self.read_pos.load(Ordering::Acquire)
+    }
+}
+
+/// Creates a ring buffer holding up to `capacity` samples, which are interleaved stereo like `sample_buf`.
+/// An odd capacity is rounded down to whole stereo pairs.
+pub fn ring_buffer(capacity: usize) -> (AudioProducer, AudioConsumer) {
+    let capacity = capacity & !1;
+    let shared = Arc::new(Shared {
+        samples: (0..capacity).map(|_| AtomicI16::new(0)).collect(),
+        write_pos: AtomicUsize::new(0),
+        read_pos: AtomicUsize::new(0),
+        overruns: AtomicUsize::new(0),
+        underruns: AtomicUsize::new(0),
+    });
+
+    (AudioProducer { shared: shared.clone() }, AudioConsumer { shared })
+}
+
+/// The emulator's end of the ring buffer.
+#[derive(Debug)]
+pub struct AudioProducer {
+    shared: Arc<Shared>,
+}
+
+impl AudioProducer {
+    /// Queues as many of `samples` as fit, keeping stereo pairs together. The rest are dropped and counted as overruns.
+    pub fn push(&mut self, samples: &[i16]) {
+        let shared = &self.shared;
+        let capacity = shared.samples.len();
+        let write_pos = shared.write_pos.load(Ordering::Relaxed);
+        let free = capacity - (write_pos - shared.read_pos.load(Ordering::Acquire));
+
+        let count = samples.len().min(free) & !1;
+        for (i, &sample) in samples[..count].iter().enumerate() {
+            shared.samples[(write_pos + i) % capacity].store(sample, Ordering::Relaxed);
+        }
+        shared.write_pos.store(write_pos + count, Ordering::Release);
+
+        if count < samples.len() {
+            shared.overruns.fetch_add(samples.len() - count, Ordering::Relaxed);
+        }
+    }
+
+    /// How many samples are waiting to be read.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most samples that can be waiting at once, which is always whole stereo pairs.
+    pub fn capacity(&self) -> usize {
+        self.shared.samples.len()
+    }
+}
+
+/// The audio output's end of the ring buffer. It can be sent to another thread.
+#[derive(Debug)]
+pub struct AudioConsumer {
+    shared: Arc<Shared>,
+}
+
+impl AudioConsumer {
+    /// Reads as many samples as are available into `out`, returning how many that was. Only whole
+    /// stereo pairs are read, so with an odd length the last sample of `out` is left alone.
+    pub fn pop(&mut self, out: &mut [i16]) -> usize {
+        let shared = &self.shared;
+        let capacity = shared.samples.len();
+        let read_pos = shared.read_pos.load(Ordering::Relaxed);
+        let available = shared.write_pos.load(Ordering::Acquire) - read_pos;
+
+        let count = out.len().min(available) & !1;
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = shared.samples[(read_pos + i) % capacity].load(Ordering::Relaxed);
+        }
+        shared.read_pos.store(read_pos + count, Ordering::Release);
+
+        count
+    }
+
+    /// Fills all of `out`, padding with silence if there isn't enough queued. The padding is counted as underruns.
+    pub fn pop_exact(&mut self, out: &mut [i16]) {
+        let count = self.pop(out);
+        if count < out.len() {
+            out[count..].fill(0);
+            self.shared.underruns.fetch_add(out.len() - count, Ordering::Relaxed);
+        }
+    }
+
+    /// How many samples are waiting to be read.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most samples that can be waiting at once, the same as the producer's.
+    pub fn capacity(&self) -> usize {
+        self.shared.samples.len()
+    }
+
+    /// Samples dropped because the buffer was full.
+    pub fn overruns(&self) -> usize {
+        self.shared.overruns.load(Ordering::Relaxed)
+    }
+
+    /// Samples of silence played because the buffer was empty.
+    pub fn underruns(&self) -> usize {
+        self.shared.underruns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = ring_buffer(6);
+        let mut out = [0; 4];
+
+        producer.push(&[1, 2, 3, 4]);
+        assert_eq!(consumer.pop(&mut out), 4);
+        producer.push(&[5, 6, 7, 8]);
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out, [5, 6, 7, 8]);
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn counts_overruns_and_underruns() {
+        let (mut producer, mut consumer) = ring_buffer(5);
+        assert_eq!((producer.capacity(), consumer.capacity()), (4, 4));
+
+        // only whole stereo pairs fit
+        producer.push(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(producer.len(), 4);
+        assert_eq!(consumer.overruns(), 2);
+
+        let mut out = [9; 6];
+        consumer.pop_exact(&mut out);
+        assert_eq!(out, [1, 2, 3, 4, 0, 0]);
+        assert_eq!(consumer.underruns(), 2);
+    }
+
+    #[test]
+    fn keeps_stereo_pairs_together() {
+        let (mut producer, mut consumer) = ring_buffer(8);
+        producer.push(&[1, 2, 3, 4]);
+
+        let mut out = [0; 3];
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out, [1, 2, 0]);
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out, [3, 4, 0]);
+    }
+}
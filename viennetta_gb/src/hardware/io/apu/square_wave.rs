@@ -1,4 +1,7 @@
 use crate::hardware::io::apu::warn;
+use crate::hardware::Model;
+use super::length_counter::LengthCounter;
+use super::envelope::Envelope;
 const WAVE_PATTERNS: [u8; 4] = [0b00000001, 0b00000011, 0b00001111, 0b11111100];
 
 #[derive(Debug, Default)]
@@ -7,17 +10,11 @@ pub struct SquareWave {
     wave_duty: u8,
     wave_position: u8,
     frequency_timer: u16,
-    length_timer: u8,
-    initial_length_timer: u8,
+    length: LengthCounter<64>,
     frequency: u16,
     pub left_pan: bool,
     pub right_pan: bool,
-    initial_volume: u8,
-    envelope_is_increase: bool,
-    envelope_period: u8,
-    length_timer_enabled: bool,
-    current_volume: u8,
-    volume_enevelope_timer: u8, 
+    envelope: Envelope,
     sweep_period: u8,
     sweep_is_downwards: bool,
     sweep_change: u8,
@@ -27,13 +24,13 @@ pub struct SquareWave {
 }
 
 impl SquareWave {
-    pub fn run_cycle(&mut self) {
+    pub fn run_cycles(&mut self, mut cycles: u32) {
         if !self.enable {
             return;
         }
 
-        self.frequency_timer -= 1;
-        if self.frequency_timer == 0 {
+        while cycles >= self.frequency_timer as u32 {
+            cycles -= self.frequency_timer as u32;
             self.wave_position += 1;
             if self.wave_position > 7 {
                 self.wave_position = 0;
@@ -41,36 +38,36 @@ impl SquareWave {
 
             self.frequency_timer = (2048 - self.frequency) * 4;
         }
+        self.frequency_timer -= cycles as u16;
     }
 
-    pub fn tick_length_timer(&mut self) {
-        if self.length_timer_enabled {
-            self.length_timer -= 1;
-            if self.length_timer == 0 {
-                self.enable = false;
-            }
-        }
+    /// T-cycles until the output next changes, or `None` if it won't by itself.
+    pub fn cycles_until_step(&self) -> Option<u32> {
+        self.enable.then_some(self.frequency_timer as u32)
     }
 
-    pub fn tick_volume_envelope(&mut self) {
-        if self.envelope_period == 0 {
-            return;
+    pub fn tick_length_timer(&mut self) {
+        if self.length.tick() {
+            self.enable = false;
         }
+    }
 
-        if self.volume_enevelope_timer != 0 {
-            self.volume_enevelope_timer -= 1;
-            if self.volume_enevelope_timer == 0 {
-                self.volume_enevelope_timer = self.envelope_period;
+    /// Clears every register. Only the length counter can survive, and only on DMG.
+    pub fn power_off(&mut self, model: Model) {
+        self.length.power_off(model);
+        *self = Self {
+            length: std::mem::take(&mut self.length),
+            ..Default::default()
+        };
+    }
 
-                if self.envelope_is_increase && self.current_volume < 0xF {
-                    self.current_volume += 1;
-                }
+    /// Writes just the length bits of NRx1, which the DMG allows while the APU is off.
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value as u16 & 0x3F);
+    }
 
-                if !self.envelope_is_increase && self.current_volume > 0x0 {
-                    self.current_volume -= 1;
-                }
-            }
-        }
+    pub fn tick_volume_envelope(&mut self) {
+        self.envelope.tick();
     }
 
     pub fn tick_freq_sweep(&mut self) {
@@ -108,13 +105,15 @@ impl SquareWave {
         }
     }
 
+    /// The DAC is powered by the top five bits of NRx2, separately from the channel being enabled.
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
     fn trigger_event(&mut self) {
-        self.enable = true;
-        if self.length_timer == 0 {
-            self.length_timer = 64;
-        }
-        self.current_volume = self.initial_volume;
-        self.volume_enevelope_timer = self.envelope_period;
+        self.enable = self.dac_enabled();
+        self.frequency_timer = (2048 - self.frequency) * 4;
+        self.envelope.trigger();
 
         self.shadow_freq = self.frequency;
         self.sweep_timer = self.sweep_period;
@@ -140,7 +139,8 @@ impl SquareWave {
         // TODO: some of these are supposed to be write-only?
         match address & 0xF {   // mask to only get the last nibble to get register regardless of channel1 or channel2
             0 => {
-                (self.sweep_period << 4)
+                0x80
+                    | (self.sweep_period << 4)
                     | if self.sweep_is_downwards { 0x8 } else { 0 }
                     | self.sweep_change
             },
@@ -148,21 +148,20 @@ impl SquareWave {
                 (self.wave_duty << 6) | 0x3F
             },
             2 | 7 => {
-                (self.initial_volume << 4)
-                    | if self.envelope_is_increase { 0x8 } else { 0 }
-                    | self.envelope_period
+                self.envelope.read()
             }
             3 | 8 => {
                 0xFF // write only
             }
             4 | 9 => {
-                (if self.length_timer_enabled { 0x40 } else { 0 }) | 0xBF
+                (if self.length.enabled { 0x40 } else { 0 }) | 0xBF
             }
             _ => { warn!("{address} not valid APU io address"); 0xFF }
         }
     }
 
-    pub fn write_io(&mut self, address: u16, value: u8) {
+    /// `clocks_length_next` is whether the next frame sequencer step clocks the length counter.
+    pub fn write_io(&mut self, address: u16, value: u8, clocks_length_next: bool) {
         match address & 0xF {   // mask to only get the last nibble to get register regardless of channel1 or channel2
             0 => {
                 self.sweep_period = (value >> 4) & 0x7;
@@ -171,21 +170,25 @@ impl SquareWave {
             }
             1 | 6 => {
                 self.wave_duty = value >> 6;
-                self.initial_length_timer = value & 0x3F;
-                self.length_timer = 64 - self.initial_length_timer;
+                self.write_length(value);
             },
             2 | 7 => {
-                self.initial_volume = value >> 4;
-                self.envelope_is_increase = value & 0x8 == 0x8;
-                self.envelope_period = value & 0x7;
+                self.envelope.write(value, self.enable);
+                if !self.dac_enabled() {
+                    self.enable = false;
+                }
             },
             3 | 8 => {
                 self.frequency = (self.frequency & 0x700) | value as u16;
             },
             4 | 9 => {
-                self.length_timer_enabled = value & 0x40 == 0x40;
                 self.frequency = ((value as u16 & 0x7) << 8) | (self.frequency & 0xFF);
-                if value & 0x80 == 0x80 {
+
+                let trigger = value & 0x80 == 0x80;
+                if self.length.write_control(value & 0x40 == 0x40, trigger, clocks_length_next) {
+                    self.enable = false;
+                }
+                if trigger {
                     self.trigger_event();
                 }
             },
@@ -193,17 +196,101 @@ impl SquareWave {
         };
     }
 
-    pub fn get_amplitude(&self) -> (u8, u8) {
+    /// The digital value going into the DAC, from 0 to 15.
+    pub fn get_amplitude(&self) -> u8 {
         if !self.enable {
-            return (0, 0);
+            return 0;
         }
         
-        let mut amplitude = (WAVE_PATTERNS[self.wave_duty as usize] >> self.wave_position) & 1;
-        amplitude *= self.current_volume;
+        let amplitude = (WAVE_PATTERNS[self.wave_duty as usize] >> self.wave_position) & 1;
+        amplitude * self.envelope.volume()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Triggers channel 1 with a 50% duty, whose first step is high, so the amplitude is the volume.
+    fn triggered(nr12: u8) -> SquareWave {
+        let mut channel = SquareWave::default();
+        channel.write_io(0xFF11, 0x80, true);
+        channel.write_io(0xFF12, nr12, true);
+        channel.write_io(0xFF14, 0x80, true);
+        channel
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut channel = triggered(0xF1);
+        assert_eq!(channel.get_amplitude(), 15);
+
+        channel.tick_volume_envelope();
+        assert_eq!(channel.get_amplitude(), 14);
+
+        let mut channel = triggered(0x0A);
+        assert_eq!(channel.get_amplitude(), 0);
+        for _ in 0..4 {
+            channel.tick_volume_envelope();
+        }
+        assert_eq!(channel.get_amplitude(), 2);
+    }
+
+    #[test]
+    fn test_envelope_period_0() {
+        let mut channel = triggered(0x80);
+        for _ in 0..16 {
+            channel.tick_volume_envelope();
+        }
+        assert_eq!(channel.get_amplitude(), 8);
+    }
+
+    #[test]
+    fn test_zombie_mode_period_0() {
+        // period 0 with the envelope still running adds 1
+        let mut channel = triggered(0x80);
+        channel.write_io(0xFF12, 0x80, true);
+        assert_eq!(channel.get_amplitude(), 9);
+        channel.write_io(0xFF12, 0x81, true);
+        assert_eq!(channel.get_amplitude(), 10);
+    }
+
+    #[test]
+    fn test_zombie_mode_decrease() {
+        // otherwise decrease mode adds 2
+        let mut channel = triggered(0x81);
+        channel.write_io(0xFF12, 0x81, true);
+        assert_eq!(channel.get_amplitude(), 10);
+
+        // and increase mode leaves it alone
+        let mut channel = triggered(0x89);
+        channel.write_io(0xFF12, 0x89, true);
+        assert_eq!(channel.get_amplitude(), 8);
+    }
 
-        let left = if self.left_pan { amplitude } else { 0 };
-        let right = if self.right_pan { amplitude } else { 0 };
+    #[test]
+    fn test_zombie_mode_direction_change() {
+        // 4 + 2, then flipped to 16 - 6
+        let mut channel = triggered(0x41);
+        channel.write_io(0xFF12, 0x49, true);
+        assert_eq!(channel.get_amplitude(), 10);
 
-        (left, right)
+        // wraps to 4 bits
+        let mut channel = triggered(0xF1);
+        channel.write_io(0xFF12, 0xF1, true);
+        assert_eq!(channel.get_amplitude(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_dac_off_disables_channel() {
+        let mut channel = triggered(0xF0);
+        assert!(channel.enable);
+
+        channel.write_io(0xFF12, 0x00, true);
+        assert!(!channel.enable);
+        assert_eq!(channel.get_amplitude(), 0);
+
+        // triggering with the DAC off doesn't enable it either
+        channel.write_io(0xFF14, 0x80, true);
+        assert!(!channel.enable);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::hardware::io::T_CYCLES_RATE;
+
+// VGM is a log of sound chip register writes with waits in between, counted in 44100 Hz samples.
+// The Game Boy's APU is chip 0xB3 in the format, added in version 1.61.
+
+const VGM_SAMPLE_RATE: u64 = 44100;
+const VERSION: u32 = 0x161;
+const HEADER_LEN: usize = 0x100;
+const GB_DMG_CLOCK_OFFSET: usize = 0x80;
+
+const CMD_GB_DMG_WRITE: u8 = 0xB3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_60HZ_FRAME: u8 = 0x62;
+const CMD_WAIT_50HZ_FRAME: u8 = 0x63;
+/// 0x70-0x7F wait 1-16 samples.
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END: u8 = 0x66;
+
+/// The GD3 tags at the end of a VGM file. Japanese versions of the names are left empty.
+#[derive(Debug, Default, Clone)]
+pub struct Gd3Tags {
+    pub track_name: String,
+    pub game_name: String,
+    pub system_name: String,
+    pub author: String,
+    pub release_date: String,
+    pub ripper: String,
+    pub notes: String,
+}
+
+#[derive(Debug)]
+pub struct VgmLogger {
+    start_cycle: u64,
+    /// Samples already waited for in `data`.
+    samples: u64,
+    data: Vec<u8>,
+    /// Offset into `data` and sample count of the loop point.
+    loop_point: Option<(usize, u64)>,
+}
+
+impl VgmLogger {
+    /// `cycle` is the APU's cycle count at the start of the log.
+    pub fn new(cycle: u64) -> Self {
+        Self {
+            start_cycle: cycle,
+            samples: 0,
+            data: vec![],
+            loop_point: None,
+        }
+    }
+
+    /// Logs a write to `address`, which is FF10-FF3F, at `cycle`.
+    pub fn log_write(&mut self, cycle: u64, address: u16, value: u8) {
+        self.wait_until(cycle);
+        self.data.extend([CMD_GB_DMG_WRITE, (address - 0xFF10) as u8, value]);
+    }
+
+    /// Players jump back to here once they reach the end.
+    pub fn mark_loop(&mut self, cycle: u64) {
+        self.wait_until(cycle);
+        self.loop_point = Some((self.data.len(), self.samples));
+    }
+
+    fn wait_until(&mut self, cycle: u64) {
+        let target = (cycle - self.start_cycle) * VGM_SAMPLE_RATE / T_CYCLES_RATE as u64;
+        let mut wait = target - self.samples;
+        self.samples = target;
+
+        while wait > 0 {
+            match wait {
+                735 => { self.data.push(CMD_WAIT_60HZ_FRAME); wait = 0; },
+                882 => { self.data.push(CMD_WAIT_50HZ_FRAME); wait = 0; },
+                1..=16 => { self.data.push(CMD_WAIT_SHORT + wait as u8 - 1); wait = 0; },
+                _ => {
+                    let chunk = wait.min(u16::MAX as u64);
+                    self.data.push(CMD_WAIT);
+                    self.data.extend((chunk as u16).to_le_bytes());
+                    wait -= chunk;
+                }
+            }
+        }
+    }
+
+    /// Ends the log at `cycle` and builds the whole file.
+    pub fn finish(mut self, cycle: u64, tags: &Gd3Tags) -> Vec<u8> {
+        self.wait_until(cycle);
+        self.data.push(CMD_END);
+
+        let mut file = vec![0; HEADER_LEN];
+        file.extend(&self.data);
+        let gd3_offset = file.len();
+        file.extend(Self::gd3(tags));
+
+        let mut set_u32 = |offset: usize, value: u32| file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        set_u32(0x00, u32::from_le_bytes(*b"Vgm "));
+        set_u32(0x08, VERSION);
+        set_u32(0x14, (gd3_offset - 0x14) as u32);
+        set_u32(0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_point {
+            set_u32(0x1C, (HEADER_LEN + offset - 0x1C) as u32);
+            set_u32(0x20, (self.samples - samples) as u32);
+        }
+        set_u32(0x34, (HEADER_LEN - 0x34) as u32);
+        set_u32(GB_DMG_CLOCK_OFFSET, T_CYCLES_RATE);
+
+        let eof_offset = file.len() - 4;
+        file[0x04..0x08].copy_from_slice(&(eof_offset as u32).to_le_bytes());
+        file
+    }
+
+    fn gd3(tags: &Gd3Tags) -> Vec<u8> {
+        let strings = [
+            &tags.track_name, "", &tags.game_name, "", &tags.system_name, "", &tags.author, "",
+            &tags.release_date, &tags.ripper, &tags.notes,
+        ];
+
+        // each string is null terminated UTF-16
+        let body: Vec<u8> = strings.iter()
+            .flat_map(|string| string.encode_utf16().chain([0]))
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+
+        let mut gd3 = b"Gd3 ".to_vec();
+        gd3.extend(0x100u32.to_le_bytes());
+        gd3.extend((body.len() as u32).to_le_bytes());
+        gd3.extend(body);
+        gd3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(file: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn finish_builds_the_file() {
+        // 952 T-cycles is 10 samples, 70857 is 745 and 6752735 is 71000
+        let mut vgm = VgmLogger::new(1000);
+        vgm.log_write(1000, 0xFF26, 0x80);
+        vgm.log_write(1952, 0xFF12, 0xF0);
+        vgm.mark_loop(71857);
+        vgm.log_write(71857, 0xFF30, 0xAB);
+        let file = vgm.finish(6753735, &Gd3Tags::default());
+
+        assert_eq!(&file[0x00..0x04], b"Vgm ");
+        assert_eq!(u32_at(&file, 0x04) as usize, file.len() - 0x04);
+        assert_eq!(u32_at(&file, 0x08), 0x161);
+        assert_eq!(u32_at(&file, 0x80), 4194304);
+
+        let data = [
+            0xB3, 0x16, 0x80,
+            0x79, 0xB3, 0x02, 0xF0,
+            0x62, 0xB3, 0x20, 0xAB,
+            // the 70255 samples left don't fit in one wait
+            0x61, 0xFF, 0xFF, 0x61, 0x70, 0x12,
+            0x66,
+        ];
+        assert_eq!(u32_at(&file, 0x34) as usize + 0x34, HEADER_LEN);
+        assert_eq!(&file[HEADER_LEN..HEADER_LEN + data.len()], &data);
+
+        assert_eq!(u32_at(&file, 0x18), 71000);
+        // the loop starts after the 60 Hz frame wait
+        assert_eq!(u32_at(&file, 0x1C) as usize + 0x1C, HEADER_LEN + 8);
+        assert_eq!(u32_at(&file, 0x20), 71000 - 745);
+
+        let gd3 = u32_at(&file, 0x14) as usize + 0x14;
+        assert_eq!(gd3, HEADER_LEN + data.len());
+        assert_eq!(&file[gd3..gd3 + 4], b"Gd3 ");
+    }
+}
@@ -0,0 +1,82 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+const HEADER_LEN: u32 = 44;
+const BYTES_PER_SAMPLE: u16 = 2;
+
+/// Writes 16-bit PCM samples out as a WAV file. The header has to be patched with the final
+/// length once recording is done, so call `finish` rather than just dropping it.
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes the header for a file of interleaved samples with `channels` channels.
+    pub fn new(mut writer: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let block_align = channels * BYTES_PER_SAMPLE;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_LEN - 8).to_le_bytes())?;     // file length - 8, patched in finish
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;                // fmt chunk length
+        writer.write_all(&1u16.to_le_bytes())?;                 // PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;  // bytes per second
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?;  // bits per sample
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;                 // data length, patched in finish
+
+        Ok(Self {
+            writer,
+            data_len: 0,
+        })
+    }
+
+    /// Writes each sample straight through, so `writer` should be buffered.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += (samples.len() * BYTES_PER_SAMPLE as usize) as u32;
+        Ok(())
+    }
+
+    /// Fills in the lengths in the header and hands back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(HEADER_LEN as u64 - 4))?;
+        self.writer.write_all(&self.data_len.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn finish_fills_in_the_lengths() {
+        let mut wav = WavWriter::new(Cursor::new(vec![]), 48000, 2).unwrap();
+        wav.write_samples(&[1, -1, 0x1234, -0x1234]).unwrap();
+        wav.write_samples(&[0x7FFF, -0x8000]).unwrap();
+        let file = wav.finish().unwrap().into_inner();
+
+        assert_eq!(file.len(), HEADER_LEN as usize + 12);
+        assert_eq!(&file[0..4], b"RIFF");
+        assert_eq!(file[4..8], (HEADER_LEN - 8 + 12).to_le_bytes());
+        assert_eq!(&file[36..40], b"data");
+        assert_eq!(file[40..44], 12u32.to_le_bytes());
+        assert_eq!(file[44..], [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xCC, 0xED, 0xFF, 0x7F, 0x00, 0x80]);
+    }
+}
@@ -1,108 +1,112 @@
 use log::warn;
+use crate::hardware::Model;
+use super::length_counter::LengthCounter;
+use super::envelope::Envelope;
 
 #[derive(Debug, Default)]
 pub struct WhiteNoise {
     pub enable: bool,
     pub right_pan: bool,
     pub left_pan: bool,
+    /// 15 bits, with the output in bit 0.
     lfsr: u16,
     lfsr_7_bit: bool,
-    frequency_timer: u16,
+    frequency_timer: u32,
     freq_divisor: u8,
     freq_shift: u8,
-    length_timer: u8,
-    length_timer_enabled: bool,
-    initial_length_timer: u8, 
-    initial_volume: u8,
-    envelope_is_increase: bool,
-    envelope_period: u8,
-    current_volume: u8,
-    volume_enevelope_timer: u8,
+    length: LengthCounter<64>,
+    envelope: Envelope,
 }
 
 impl WhiteNoise {
+    /// The DAC is powered by the top five bits of NR42, separately from the channel being enabled.
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
     fn trigger_event(&mut self) {
-        self.enable = true;
-        if self.length_timer == 0 {
-            self.length_timer = 64;
-        }
-        self.current_volume = self.initial_volume;
-        self.volume_enevelope_timer = self.envelope_period;
-        self.lfsr = 0x7F;
+        self.enable = self.dac_enabled();
+        self.reset_freq_timer();
+        self.envelope.trigger();
+        self.lfsr = 0;
         //println!("triggered");
     }
 
+    /// The LFSR is clocked every divisor << shift T-cycles, where a divisor code of 0 means 8 rather than 0.
     fn reset_freq_timer(&mut self) {
         let divisor = [8, 16, 32, 48, 64, 80, 96, 112][self.freq_divisor as usize];
         self.frequency_timer = divisor << self.freq_shift;
     }
 
-    pub fn run_cycle(&mut self) {
-        self.frequency_timer -= 1;
-        if self.frequency_timer == 0 {
+    pub fn run_cycles(&mut self, mut cycles: u32) {
+        if !self.enable {
+            return;
+        }
+
+        while cycles >= self.frequency_timer {
+            cycles -= self.frequency_timer;
             self.reset_freq_timer();
 
-            let xor_result = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
-            self.lfsr >>= 1;
-            self.lfsr |= xor_result << 14;
-            if self.lfsr_7_bit {
-                self.lfsr &= !(1 << 6);
-                self.lfsr |= xor_result >> 6;
+            // shifts of 14 and 15 don't get any clocks through to the LFSR
+            if self.freq_shift < 14 {
+                self.clock_lfsr();
             }
         }
+        self.frequency_timer -= cycles;
     }
 
-    pub fn tick_volume_envelope(&mut self) {
-        if self.envelope_period == 0 {
-            return;
-        }
-
-        if self.volume_enevelope_timer != 0 {
-            self.volume_enevelope_timer -= 1;
-            if self.volume_enevelope_timer == 0 {
-                self.volume_enevelope_timer = self.envelope_period;
-
-                if self.envelope_is_increase && self.current_volume < 0xF {
-                    self.current_volume += 1;
-                }
+    /// T-cycles until the LFSR is next clocked, or `None` if it won't be.
+    pub fn cycles_until_step(&self) -> Option<u32> {
+        (self.enable && self.freq_shift < 14).then_some(self.frequency_timer)
+    }
 
-                if !self.envelope_is_increase && self.current_volume > 0x0 {
-                    self.current_volume -= 1;
-                }
-            }
+    fn clock_lfsr(&mut self) {
+        let new_bit = !(self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (new_bit << 14);
+        if self.lfsr_7_bit {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (new_bit << 6);
         }
     }
 
+    pub fn tick_volume_envelope(&mut self) {
+        self.envelope.tick();
+    }
+
     pub fn tick_length_timer(&mut self) {
-        if self.length_timer_enabled {
-            self.length_timer -= 1;
-            if self.length_timer == 0 {
-                self.enable = false;
-            }
+        if self.length.tick() {
+            self.enable = false;
         }
     }
 
-    pub fn get_amplitude(&self) -> (u8, u8) {
+    /// Clears every register. Only the length counter can survive, and only on DMG.
+    pub fn power_off(&mut self, model: Model) {
+        self.length.power_off(model);
+        *self = Self {
+            length: std::mem::take(&mut self.length),
+            ..Default::default()
+        };
+    }
+
+    /// Writes NR41, which the DMG allows while the APU is off.
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value as u16 & 0x3F);
+    }
+
+    /// The digital value going into the DAC, from 0 to 15.
+    pub fn get_amplitude(&self) -> u8 {
         if !self.enable {
-            return (0, 0);
+            return 0;
         }
         //println!("OUTPUTTING SAMPLE");
-        let mut amplitude = (!self.lfsr & 1) as u8;
-        amplitude *= self.current_volume;
-
-        let left = if self.left_pan { amplitude } else { 0 };
-        let right = if self.right_pan { amplitude } else { 0 };
-
-        (left, right)
+        let amplitude = (self.lfsr & 1) as u8;
+        amplitude * self.envelope.volume()
     }
 
     pub fn read_io(&self, address: u16) -> u8 {
         match address & 0xF {
             0 => 0xFF,
             1 => {
-                (self.initial_volume << 4)
-                    | if self.envelope_is_increase { 0x8 } else { 0 }
-                    | self.envelope_period
+                self.envelope.read()
             },
             2 => {
                 (self.freq_shift << 4)
@@ -110,36 +114,117 @@ impl WhiteNoise {
                     | self.freq_divisor
             },
             3 => {
-                if self.length_timer_enabled { 0x40 } else { 0 }
+                (if self.length.enabled { 0x40 } else { 0 }) | 0xBF
             },
             _ => { warn!("{address} not valid APU io address"); 0xFF }
         }
     }
 
-    pub fn write_io(&mut self, address: u16, value: u8) {
+    /// `clocks_length_next` is whether the next frame sequencer step clocks the length counter.
+    pub fn write_io(&mut self, address: u16, value: u8, clocks_length_next: bool) {
         match address & 0xF {
-            0 => {
-                self.initial_length_timer = value & 0x3F;
-                self.length_timer = 64 - self.initial_length_timer;
-            },
+            0 => self.write_length(value),
             1 => {
-                self.initial_volume = value >> 4;
-                self.envelope_is_increase = value & 0x8 == 0x8;
-                self.envelope_period = value & 0x7;
+                self.envelope.write(value, self.enable);
+                if !self.dac_enabled() {
+                    self.enable = false;
+                }
             },
             2 => {
                 self.freq_divisor = value & 0x7;
-                self.lfsr_7_bit = value & 0x8 == 1;
+                self.lfsr_7_bit = value & 0x8 == 0x8;
                 self.freq_shift = value >> 4;
-                self.reset_freq_timer();
             },
             3 => {
-                if value & 0x80 == 0x80 {
+                let trigger = value & 0x80 == 0x80;
+                if self.length.write_control(value & 0x40 == 0x40, trigger, clocks_length_next) {
+                    self.enable = false;
+                }
+                if trigger {
                     self.trigger_event();
                 }
-                self.length_timer_enabled = value & 0x40 == 0x40;
             }
             _ => warn!("{address} not valid APU io address")
         };
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggered(nr43: u8) -> WhiteNoise {
+        let mut channel = WhiteNoise::default();
+        channel.write_io(0xFF21, 0xF0, true);
+        channel.write_io(0xFF22, nr43, true);
+        channel.write_io(0xFF23, 0x80, true);
+        channel
+    }
+
+    fn run(channel: &mut WhiteNoise, cycles: u32) {
+        for _ in 0..cycles {
+            channel.run_cycles(1);
+        }
+    }
+
+    #[test]
+    fn test_lfsr_output() {
+        // the LFSR starts at 0 and shifts in 1s, which reach bit 0 on the 15th clock
+        let mut channel = triggered(0x00);
+        assert_eq!(channel.get_amplitude(), 0);
+        run(&mut channel, 8 * 14);
+        assert_eq!(channel.get_amplitude(), 0);
+        run(&mut channel, 8);
+        assert_eq!(channel.get_amplitude(), 15);
+    }
+
+    #[test]
+    fn test_lfsr_7_bit() {
+        // short mode copies the new bit into bit 6 too, so it gets there after 7 clocks
+        let mut channel = triggered(0x08);
+        run(&mut channel, 8 * 6);
+        assert_eq!(channel.get_amplitude(), 0);
+        run(&mut channel, 8);
+        assert_eq!(channel.get_amplitude(), 15);
+        assert_eq!(channel.read_io(0xFF22), 0x08);
+    }
+
+    #[test]
+    fn test_divisor_timing() {
+        // a divisor code of 0 clocks every 8 T-cycles, the others every 16 * code
+        let mut channel = triggered(0x01);
+        run(&mut channel, 16 * 15 - 1);
+        assert_eq!(channel.get_amplitude(), 0);
+        run(&mut channel, 1);
+        assert_eq!(channel.get_amplitude(), 15);
+
+        // the shift multiplies the period by a power of 2
+        let mut channel = triggered(0x20);
+        run(&mut channel, 32 * 15 - 1);
+        assert_eq!(channel.get_amplitude(), 0);
+        run(&mut channel, 1);
+        assert_eq!(channel.get_amplitude(), 15);
+    }
+
+    #[test]
+    fn test_shift_14_and_15_stop_lfsr() {
+        for nr43 in [0xE0, 0xF0] {
+            let mut channel = triggered(nr43);
+            run(&mut channel, (8 << 15) * 16);
+            assert_eq!(channel.lfsr, 0);
+            assert_eq!(channel.get_amplitude(), 0);
+        }
+    }
+
+    #[test]
+    fn test_zombie_mode() {
+        // period 0 with the envelope running adds 1, and 15 + 1 wraps around to 0
+        let mut channel = triggered(0x00);
+        run(&mut channel, 8 * 15);
+        channel.write_io(0xFF21, 0xF1, true);
+        assert_eq!(channel.get_amplitude(), 0);
+
+        // then decrease mode adds 2
+        channel.write_io(0xFF21, 0xF1, true);
+        assert_eq!(channel.get_amplitude(), 2);
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// The CGB's infrared port, RP (FF56). Bit 0 turns the LED on, bit 1 reads 0 while light is hitting
+// the sensor, and bits 6-7 have to both be set for bit 1 to read anything at all. Games send data
+// as the timing between pulses, so a peer is told when the LED changes and asked about the sensor
+// at a given cycle, in T-cycles at single speed.
+
+/// Whatever the IR port is pointed at.
+pub trait IrPeer: fmt::Debug + Send {
+    /// Our LED turned on or off at `cycle`.
+    fn set_led(&mut self, on: bool, cycle: u64);
+
+    /// Whether any light is reaching our sensor at `cycle`.
+    fn receiving(&self, cycle: u64) -> bool;
+}
+
+/// Nothing there, so the sensor never sees anything.
+#[derive(Debug, Default)]
+pub struct NoIrPeer;
+
+impl IrPeer for NoIrPeer {
+    fn set_led(&mut self, _on: bool, _cycle: u64) {}
+
+    fn receiving(&self, _cycle: u64) -> bool {
+        false
+    }
+}
+
+/// Plays back a fixed list of pulses and records what our LED does, for testing. Clones share
+/// the record, so keep one to read it back from after connecting another.
+#[derive(Debug, Default, Clone)]
+pub struct ScriptedIr {
+    /// Start and end cycles of each pulse of light to send.
+    pub pulses: Vec<(u64, u64)>,
+    led_changes: Arc<Mutex<Vec<(u64, bool)>>>,
+}
+
+impl ScriptedIr {
+    pub fn new(pulses: Vec<(u64, u64)>) -> Self {
+        Self {
+            pulses,
+            ..Default::default()
+        }
+    }
+
+    /// Every change of our LED, with the cycle it happened.
+    pub fn led_changes(&self) -> Vec<(u64, bool)> {
+        self.led_changes.lock().unwrap().clone()
+    }
+}
+
+impl IrPeer for ScriptedIr {
+    fn set_led(&mut self, on: bool, cycle: u64) {
+        self.led_changes.lock().unwrap().push((cycle, on));
+    }
+
+    fn receiving(&self, cycle: u64) -> bool {
+        self.pulses.iter().any(|&(start, end)| (start..end).contains(&cycle))
+    }
+}
+
+/// How far back LED changes are kept, enough for a console that's running a little behind.
+const HISTORY_CYCLES: u64 = 1 << 16;
+
+#[derive(Debug, Default)]
+struct Beam {
+    /// Changes of each side's LED, oldest first.
+    changes: [VecDeque<(u64, bool)>; 2],
+}
+
+/// One side of two Game Boys pointed at each other, made by `ir_link`. They should be run in
+/// lockstep, like `LinkedGameBoys` does, so each sees the other's LED at the right time.
+#[derive(Debug)]
+pub struct IrPort {
+    side: usize,
+    beam: Arc<Mutex<Beam>>,
+}
+
+pub fn ir_link() -> (IrPort, IrPort) {
+    let beam = Arc::new(Mutex::new(Beam::default()));
+    (IrPort { side: 0, beam: beam.clone() }, IrPort { side: 1, beam })
+}
+
+impl IrPeer for IrPort {
+    fn set_led(&mut self, on: bool, cycle: u64) {
+        let mut beam = self.beam.lock().unwrap();
+        let changes = &mut beam.changes[self.side];
+        changes.push_back((cycle, on));
+
+        // keep the latest change before the cut off, as that's the LED's state from then on
+        while changes.len() > 1 && changes[1].0 + HISTORY_CYCLES < cycle {
+            changes.pop_front();
+        }
+    }
+
+    fn receiving(&self, cycle: u64) -> bool {
+        let beam = self.beam.lock().unwrap();
+        beam.changes[1 - self.side].iter()
+            .take_while(|&&(changed, _)| changed <= cycle)
+            .last()
+            .is_some_and(|&(_, on)| on)
+    }
+}
+
+#[derive(Debug)]
+pub struct Infrared {
+    led: bool,
+    /// Bits 6-7 of RP.
+    read_enable: u8,
+    /// T-cycles at single speed since power on.
+    cycles: u64,
+    peer: Box<dyn IrPeer>,
+}
+
+impl Default for Infrared {
+    fn default() -> Self {
+        Self {
+            led: false,
+            read_enable: 0,
+            cycles: 0,
+            peer: Box::new(NoIrPeer),
+        }
+    }
+}
+
+impl Infrared {
+    pub fn connect(&mut self, peer: Box<dyn IrPeer>) -> Box<dyn IrPeer> {
+        std::mem::replace(&mut self.peer, peer)
+    }
+
+    pub fn run_cycles(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+
+    pub fn read(&self) -> u8 {
+        let not_receiving = self.read_enable != 0xC0 || !self.peer.receiving(self.cycles);
+        self.read_enable | 0x3C | if not_receiving { 0x02 } else { 0 } | self.led as u8
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.read_enable = value & 0xC0;
+
+        let led = value & 1 == 1;
+        if led != self.led {
+            self.led = led;
+            self.peer.set_led(led, self.cycles);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_needs_enabling() {
+        let mut infrared = Infrared::default();
+        infrared.connect(Box::new(ScriptedIr::new(vec![(0, 100)])));
+
+        assert_eq!(infrared.read(), 0x3E);
+        infrared.write(0xC0);
+        assert_eq!(infrared.read(), 0xFC);
+        infrared.run_cycles(100);
+        assert_eq!(infrared.read(), 0xFE);
+    }
+
+    #[test]
+    fn pair_sees_each_other() {
+        let (mut left, right) = ir_link();
+
+        left.set_led(true, 100);
+        left.set_led(false, 200);
+        assert!(!right.receiving(99));
+        assert!(right.receiving(150));
+        assert!(!right.receiving(200));
+        assert!(!left.receiving(150));
+    }
+}
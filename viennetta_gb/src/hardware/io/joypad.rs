@@ -1,43 +1,126 @@
 use bitflags::bitflags;
 
+// P1 (FF00) is a matrix: bits 4 and 5 pull the d-pad and the other buttons' rows low when they're
+// cleared, and a pressed button then pulls its column, bits 0-3, low. With both rows selected the
+// two sets are effectively ANDed together, and with neither every column reads 1. Any column going
+// from high to low requests the joypad interrupt, and wakes the CPU from STOP.
+
 bitflags! {
-    #[derive(Debug, Default)]
+    /// Buttons held down. The d-pad is the low nibble and the rest the high nibble, in the same
+    /// order as their P1 bits.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
     pub struct Buttons: u8 {
-        const A = 1 << 0;
-        const B = 1 << 1;
-        const Select = 1 << 2;
-        const Start = 1 << 3;
-        const Right = 1 << 4;
-        const Left = 1 << 5;
-        const Up = 1 << 6;
-        const Down = 1 << 7;
+        const Right = 1 << 0;
+        const Left = 1 << 1;
+        const Up = 1 << 2;
+        const Down = 1 << 3;
+        const A = 1 << 4;
+        const B = 1 << 5;
+        const Select = 1 << 6;
+        const Start = 1 << 7;
     }
 }
 
 #[derive(Debug, Default)]
 pub struct Joypad {
-    type_select: u8,
-    buttons_pressed: Buttons,
+    /// Bits 4 and 5 of P1, which are active low.
+    select: u8,
+    pressed: Buttons,
+    /// Set when a column falls, until the MMU takes it with `take_interrupt`.
+    interrupt: bool,
 }
 
 impl Joypad {
-    pub fn update_state(&mut self, buttons: Buttons) {
-        self.buttons_pressed = buttons;
+    /// Sets which buttons are held down.
+    pub fn set_pressed(&mut self, buttons: Buttons) {
+        let columns = self.columns();
+        self.pressed = buttons;
+        self.check_falling_edge(columns);
     }
 
-    pub fn read(&self) -> u8 {
-        if self.type_select & 0x10 == 0x00 {
-            self.type_select | (self.buttons_pressed.bits() & 0xF)
+    pub fn pressed(&self) -> Buttons {
+        self.pressed
+    }
+
+    /// Bits 0-3 of P1, low for the pressed buttons in the selected rows.
+    fn columns(&self) -> u8 {
+        let mut pulled_low = 0;
+        if self.select & 0x10 == 0 {
+            pulled_low |= self.pressed.bits() & 0xF;
         }
-        else if self.type_select & 0x20 == 0x00 {
-            self.type_select | (self.buttons_pressed.bits() >> 4)
+        if self.select & 0x20 == 0 {
+            pulled_low |= self.pressed.bits() >> 4;
         }
-        else {
-            self.type_select
+
+        !pulled_low & 0xF
+    }
+
+    fn check_falling_edge(&mut self, old_columns: u8) {
+        if old_columns & !self.columns() != 0 {
+            self.interrupt = true;
         }
     }
 
+    /// Whether any column has fallen since last time.
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    /// Whether a button in a selected row is held, which is what brings the CPU out of STOP.
+    pub fn any_selected_pressed(&self) -> bool {
+        self.columns() != 0xF
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.columns()
+    }
+
     pub fn write(&mut self, value: u8) {
-        self.type_select = value & 0x30;
+        let columns = self.columns();
+        self.select = value & 0x30;
+        self.check_falling_edge(columns);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_selection() {
+        let mut joypad = Joypad::default();
+        joypad.set_pressed(Buttons::Right | Buttons::Start);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xEE);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD7);
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC6);
+    }
+
+    #[test]
+    fn interrupt_on_falling_edge() {
+        let mut joypad = Joypad::default();
+        joypad.write(0x20);
+        joypad.take_interrupt();
+
+        // the other row isn't selected, so nothing changes
+        joypad.set_pressed(Buttons::A);
+        assert!(!joypad.take_interrupt());
+
+        joypad.set_pressed(Buttons::A | Buttons::Down);
+        assert!(joypad.take_interrupt());
+
+        // releasing is a rising edge
+        joypad.set_pressed(Buttons::empty());
+        assert!(!joypad.take_interrupt());
+
+        // selecting a row with a button already held is a falling edge too
+        joypad.set_pressed(Buttons::A);
+        joypad.write(0x10);
+        assert!(joypad.take_interrupt());
+    }
+}
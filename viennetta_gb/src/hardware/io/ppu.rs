@@ -1,6 +1,8 @@
 use super::Interrupts;
+use crate::hardware::Model;
 use bitflags::bitflags;
 use dbg_hex::dbg_hex;
+use log::trace;
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
@@ -12,7 +14,15 @@ const LINE_LEN: u16 = 456;
 const VBLANK_START: u8 = 144;
 const VBLANK_LEN: u8 = 10;
 const FRAME_SCANLINES: u8 = VBLANK_START + VBLANK_LEN;
-const DMG_COLOURS: [u16; 4] = [0x7FFF, 0x5AB9, 0x35A5, 0x0000];
+/// The points in a line where the mode or STAT line can change, from LY=LYC and line 153's quirks
+/// in the first few dots to the mode changes. Nothing happens on the dots in between.
+const EVENT_DOTS: [u16; 6] = [4, 8, 12, DRAW_START, HBLANK_START, LINE_LEN];
+pub const DMG_COLOURS: [u16; 4] = [0x7FFF, 0x5AB9, 0x35A5, 0x0000];
+
+/// Which of the 4 DMG shades a pixel of a DMG frame is.
+pub fn dmg_shade(pixel: u16) -> usize {
+    DMG_COLOURS.iter().position(|&colour| colour == pixel).unwrap_or(0)
+}
 
 bitflags! {
     #[derive(Debug, Clone, Copy)]
@@ -45,6 +55,14 @@ enum Mode {
     Drawing = 3,
 }
 
+/// The kinds of CPU bus activity that corrupt OAM on DMG when they land in FE00-FEFF during mode 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OamCorruption {
+    Write,
+    Read,
+    ReadIncrease,
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum Colour {
@@ -82,6 +100,7 @@ pub struct DMGPalettes {
 
 #[derive(Default, Debug, Clone, Copy)]
 struct Object {
+    index: u8,
     x: u8,
     y: u8,
     tile: u8,
@@ -94,9 +113,10 @@ struct Object {
 }
 
 impl Object {
-    fn from_bytes(bytes: u32) -> Self {
+    fn from_bytes(index: u8, bytes: u32) -> Self {
         let flags = bytes & 0xFF;
         Self {
+            index,
             x: ((bytes >> 16) & 0xFF) as u8,
             y: (bytes >> 24) as u8,
             tile: ((bytes >> 8) & 0xFF) as u8,
@@ -114,6 +134,33 @@ impl Object {
     }
 }
 
+/// A background/window pixel waiting to be mixed with the objects on the line.
+#[derive(Default, Debug, Clone, Copy)]
+struct BgPixel {
+    colour: u8,
+    cgb_palette: u8,
+    priority: bool,
+}
+
+/// The last background/window tile fetched while drawing a line.
+#[derive(Debug, Clone, Copy)]
+struct FetchedTile {
+    tile_x: usize,
+    fetcher_y: usize,
+    tilemap: bool,
+    attrib: TileAttrib,
+    data: (u8, u8),
+}
+
+/// The highest priority opaque object pixel at a position on the line.
+#[derive(Debug, Clone, Copy)]
+struct ObjPixel {
+    colour: u8,
+    dmg_palette: DMGPalette,
+    cgb_palette: u8,
+    behind_bg: bool,
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 struct TileAttrib {
     priority: bool,
@@ -159,16 +206,21 @@ pub struct PPU {
     cgb_obj_pals: [u16; 32],
     sprite_buffer: Vec<Object>,
     pub debug: bool,
-    scheduled_stat_update: bool,
+    stat_write_glitch: bool,
+    /// Set by register writes, until the next dot has been run with the new values.
+    registers_changed: bool,
     window_triggered: bool,
+    window_full_line: bool,
     win_line_counter: u8,
     is_cgb: bool,
+    model: Model,
+    obj_priority_by_x: bool,
     bgpi: u8,
     obpi: u8,
 }
 
-impl Default for PPU {
-    fn default() -> Self {
+impl PPU {
+    pub fn new(model: Model) -> Self {
         Self {
             mode: Mode::OAMScan,
             line_y: 0,
@@ -189,10 +241,14 @@ impl Default for PPU {
             dmg_palettes: DMGPalettes::default(),
             sprite_buffer: vec![],
             debug: false,
-            scheduled_stat_update: false,
+            stat_write_glitch: false,
+            registers_changed: false,
             window_triggered: false,
+            window_full_line: false,
             win_line_counter: 0,
-            is_cgb: true,
+            is_cgb: model == Model::Cgb,
+            model,
+            obj_priority_by_x: model == Model::Dmg,
             cgb_bg_pals: [0; 32],
             cgb_obj_pals: [0; 32],
             bgpi: 0,
@@ -206,40 +262,153 @@ impl PPU {
         self.lcd
     }
 
+    /// The CPU loses access to VRAM while mode 3 runs on each visible line. Mode 3 is always 172
+    /// dots here, so that's fixed. Reads are cut off on its first dot, but the lock on writes only
+    /// comes in a dot later, so a write landing right as mode 3 starts still goes through.
+    fn vram_blocked(&self, write: bool) -> bool {
+        let locked_from = if write { DRAW_START + 1 } else { DRAW_START };
+
+        self.lcdc.contains(LCDC::PpuEnable)
+            && self.line_y < VBLANK_START
+            && (locked_from..HBLANK_START).contains(&self.cycles_line)
+    }
+
+    /// OAM is locked from the first dot of the OAM scan until mode 3 ends. The first line after the
+    /// LCD is switched on has no OAM scan, so there it's only locked from the start of mode 3. On
+    /// DMG the write lock drops for the dot the scan hands over to mode 3, like VRAM's comes in late.
+    fn oam_blocked(&self, write: bool) -> bool {
+        let no_scan = self.mode == Mode::HBlank && self.cycles_line < DRAW_START;
+        let locked_from = if no_scan { DRAW_START } else { 0 };
+        let handover = write && self.model == Model::Dmg && self.cycles_line == DRAW_START;
+
+        self.lcdc.contains(LCDC::PpuEnable)
+            && self.line_y < VBLANK_START
+            && (locked_from..HBLANK_START).contains(&self.cycles_line)
+            && !handover
+    }
+
+    fn vram_index(&self, address: u16) -> usize {
+        (address + 0x2000 * self.vram_bank as u16) as usize
+    }
+
     pub fn read_vram(&self, address: u16) -> u8 {
-        if self.mode != Mode::Drawing {
-            self.vram[(address + 0x2000 * self.vram_bank as u16) as usize]
-        }
-        else {
-            println!("read during mode 3");
-            0xFF
-            //self.vram[(address + 0x2000 * self.vram_bank as u16) as usize]
+        if self.vram_blocked(false) {
+            trace!("VRAM read from {:04X} blocked (LY: {}, dot: {})", address + 0x8000, self.line_y, self.cycles_line);
+            return 0xFF;
         }
+
+        self.vram[self.vram_index(address)]
     }
 
     pub fn write_vram(&mut self, address: u16, value: u8) {
-        if self.mode != Mode::Drawing {
-            self.vram[(address + 0x2000 * self.vram_bank as u16) as usize] = value;
-        }
-        else {
-            println!("write during mode 3 {:04X}, {:02X}", address + 0x8000, value);
-            //self.vram[(address + 0x2000 * self.vram_bank as u16) as usize] = value;
+        if self.vram_blocked(true) {
+            trace!("VRAM write of {:02X} to {:04X} blocked (LY: {}, dot: {})", value, address + 0x8000, self.line_y, self.cycles_line);
+            return;
         }
+
+        self.vram[self.vram_index(address)] = value;
     }
 
     pub fn read_oam(&self, address: u16) -> u8 {
-        if self.mode == Mode::HBlank || self.mode == Mode::VBlank {
-            self.oam[address as usize]
-        }
-        else {
-            0xFF
+        if self.oam_blocked(false) {
+            trace!("OAM read from {:04X} blocked (LY: {}, dot: {})", address + 0xFE00, self.line_y, self.cycles_line);
+            return 0xFF;
         }
+
+        self.oam[address as usize]
     }
 
     pub fn write_oam(&mut self, address: u16, value: u8) {
-        if self.mode == Mode::HBlank || self.mode == Mode::VBlank {
-            self.oam[address as usize] = value;
+        if self.oam_blocked(true) {
+            trace!("OAM write of {:02X} to {:04X} blocked (LY: {}, dot: {})", value, address + 0xFE00, self.line_y, self.cycles_line);
+            return;
+        }
+
+        self.oam[address as usize] = value;
+    }
+
+    /// Used by the DMA engines, which aren't subject to the PPU's bus locking.
+    pub fn dma_write_vram(&mut self, address: u16, value: u8) {
+        let index = self.vram_index(address);
+        self.vram[index] = value;
+    }
+
+    /// Used by the DMA engines, which aren't subject to the PPU's bus locking.
+    pub fn dma_write_oam(&mut self, address: u16, value: u8) {
+        self.oam[address as usize] = value;
+    }
+
+    /// Applies the DMG OAM bug to the row the OAM scan is currently reading.
+    /// The caller is responsible for checking that the address involved is in FE00-FEFF.
+    pub fn oam_bug(&mut self, kind: OamCorruption) {
+        if self.model != Model::Dmg || !self.lcdc.contains(LCDC::PpuEnable) || self.mode != Mode::OAMScan {
+            return;
+        }
+
+        // the scan goes through one 8 byte row (two objects) every M-cycle
+        let row = (self.cycles_line / 4) as usize;
+        if row == 0 || row >= 20 {
+            return;
         }
+
+        match kind {
+            OamCorruption::Write => {
+                let (a, b, c) = (self.oam_word(row, 0), self.oam_word(row - 1, 0), self.oam_word(row - 1, 2));
+                self.set_oam_word(row, 0, ((a ^ c) & (b ^ c)) ^ c);
+                self.copy_oam_row_tail(row - 1, row);
+            }
+            OamCorruption::Read => {
+                self.oam_bug_read(row);
+            }
+            OamCorruption::ReadIncrease => {
+                if (4..19).contains(&row) {
+                    let a = self.oam_word(row - 2, 0);
+                    let b = self.oam_word(row - 1, 0);
+                    let c = self.oam_word(row, 0);
+                    let d = self.oam_word(row - 1, 2);
+                    self.set_oam_word(row - 1, 0, (b & (a | c | d)) | (a & c & d));
+
+                    let prev = (row - 1) * 8;
+                    self.oam.copy_within(prev..prev + 8, row * 8);
+                    self.oam.copy_within(prev..prev + 8, (row - 2) * 8);
+                }
+
+                self.oam_bug_read(row);
+            }
+        }
+
+        trace!("OAM bug ({kind:?}) corrupted row {row} (LY: {}, dot: {})", self.line_y, self.cycles_line);
+    }
+
+    fn oam_bug_read(&mut self, row: usize) {
+        let (a, b, c) = (self.oam_word(row, 0), self.oam_word(row - 1, 0), self.oam_word(row - 1, 2));
+        self.set_oam_word(row, 0, b | (a & c));
+        self.copy_oam_row_tail(row - 1, row);
+    }
+
+    fn oam_word(&self, row: usize, word: usize) -> u16 {
+        let index = row * 8 + word * 2;
+        u16::from_le_bytes([self.oam[index], self.oam[index + 1]])
+    }
+
+    fn set_oam_word(&mut self, row: usize, word: usize, value: u16) {
+        let index = row * 8 + word * 2;
+        self.oam[index..index + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Copies the last three words of one OAM row over another.
+    fn copy_oam_row_tail(&mut self, from: usize, to: usize) {
+        self.oam.copy_within(from * 8 + 2..from * 8 + 8, to * 8 + 2);
+    }
+
+    /// Reads VRAM from the current bank regardless of PPU mode, for the DMA engines and debugging tools.
+    pub fn debug_read_vram(&self, address: u16) -> u8 {
+        self.vram[self.vram_index(address)]
+    }
+
+    /// Reads OAM regardless of PPU mode, for the DMA engines and debugging tools.
+    pub fn debug_read_oam(&self, address: u16) -> u8 {
+        self.oam[address as usize]
     }
     
     pub fn dump_regs(&self) {
@@ -261,7 +430,7 @@ impl PPU {
             0xFF41 => self.status | 0x80,
             0xFF42 => self.scroll_y,
             0xFF43 => self.scroll_x,
-            0xFF44 => self.line_y,
+            0xFF44 => self.ly_register(),
             0xFF45 => self.line_compare,
             0xFF47 => self.dmg_palettes.bg_palette,
             0xFF48 => self.dmg_palettes.obj0_palette,
@@ -273,15 +442,17 @@ impl PPU {
             0xFF69 => Self::read_io_pal(&self.cgb_bg_pals, self.bgpi as usize),
             0xFF6A => self.obpi,
             0xFF6B => Self::read_io_pal(&self.cgb_obj_pals, self.obpi as usize),
-            0xFF6C => if self.is_cgb { 0 } else { 1 },
+            0xFF6C if self.model == Model::Cgb => 0xFE | self.obj_priority_by_x as u8,
             _ => 0,
         }
     }
 
     pub fn write_io(&mut self, address: u16, value: u8) {
+        self.registers_changed = true;
+
         match address {
             0xFF40 => self.lcdc = LCDC::from_bits(value).unwrap(),
-            0xFF41 => { self.scheduled_stat_update = true; self.status = (value & 0xFC) | 0x80 },
+            0xFF41 => self.write_stat(value),
             0xFF42 => self.scroll_y = value,
             0xFF43 => self.scroll_x = value,
             0xFF45 => self.line_compare = value,
@@ -295,7 +466,8 @@ impl PPU {
             0xFF69 => Self::write_io_pal(&mut self.cgb_bg_pals, &mut self.bgpi, value, true),
             0xFF6A => self.obpi = value,
             0xFF6B => Self::write_io_pal(&mut self.cgb_obj_pals, &mut self.obpi, value, false),
-            0xFF6C => self.is_cgb = (value & 1) == 0,
+            0xFF4C if self.model == Model::Cgb => self.is_cgb = value & 0x04 == 0,
+            0xFF6C if self.model == Model::Cgb => self.obj_priority_by_x = value & 1 == 1,
             _ => {},
         }
     }
@@ -327,12 +499,21 @@ impl PPU {
         }
     }
 
-    pub fn run_cycles(&mut self, cycles: u8) -> Interrupts {
+    pub fn run_cycles(&mut self, cycles: u32) -> Interrupts {
         let mut interrupts = Interrupts::empty();
 
         if self.lcdc.contains(LCDC::PpuEnable) {
-            for _ in 0..cycles {
-                interrupts |= self.run_cycle();
+            let mut left = cycles;
+            while left > 0 {
+                // the dots before the next event only move the line along
+                let quiet = self.cycles_until_event().map_or(0, |cycles| cycles - 1).min(left);
+                self.cycles_line += quiet as u16;
+                left -= quiet;
+
+                if left > 0 {
+                    interrupts |= self.run_cycle();
+                    left -= 1;
+                }
             }
         }
         else {
@@ -341,7 +522,12 @@ impl PPU {
             self.line_x = 0;
             self.status &= 0xFC;
             self.cycles_line = 0;
-            self.mode = Mode::VBlank;
+            // the LCD restarts on line 0 without an OAM scan, so STAT reads mode 0 until mode 3
+            self.mode = Mode::HBlank;
+            self.sprite_buffer.clear();
+            self.win_line_counter = 0;
+            self.window_triggered = false;
+            self.window_full_line = false;
         }
 
         interrupts
@@ -351,164 +537,187 @@ impl PPU {
         let mut interrupts = Interrupts::empty();
         interrupts |= self.update_mode();
         interrupts |= self.update_stat();
-        
+        self.registers_changed = false;
+
         interrupts
     }
-                                                                                       
-    fn update_lcd(&mut self) {
-        let mut pixels = [(0, DMGPalette::Background); WIDTH];
-        let mut window_occured = false;
-        let mut priority = [true; WIDTH];
-
-        for tile_num in 0..(WIDTH / 8) + 1 {
-            let window = self.line_x + 7 >= self.win_x && self.line_y >= self.win_y && self.lcdc.contains(LCDC::WinEnable);
 
-            let fetcher_x;
-            let mut fetcher_y;
-            let tilemap;
-            
-            if window {
-                window_occured = true;
-                fetcher_x = (((self.line_x - self.win_x + 7) / 8) & 0x1F) as usize;
-                fetcher_y = (self.win_line_counter) as usize;
-                tilemap = self.lcdc.contains(LCDC::WinTileMap);
-            }
-            else {
-                fetcher_x = (((self.scroll_x / 8) + (tile_num as u8)) & 0x1F) as usize;
-                fetcher_y = self.line_y.wrapping_add(self.scroll_y) as usize;
-                tilemap = self.lcdc.contains(LCDC::BgTileMap);
-            }
-            let tile_data_area = self.lcdc.contains(LCDC::BgTileData);
-            
-            let tile = self.fetch_tile(fetcher_x, fetcher_y, tilemap, !window);
-            let attrib = self.fetch_tile_attrib(fetcher_x, fetcher_y, tilemap);
+    /// Dots until the next one that might do more than move the line along, or `None` if the LCD is off.
+    pub fn cycles_until_event(&self) -> Option<u32> {
+        if !self.lcdc.contains(LCDC::PpuEnable) {
+            return None;
+        }
+        if self.registers_changed || self.stat_write_glitch {
+            return Some(1);
+        }
 
-            //let tile = 0x00;
-            
-            if attrib.y_flip {
-                fetcher_y = 7 - fetcher_y;
-            }
-            
-            let fetcher_offset = (fetcher_y % 8) * 2;
-            let tile = self.fetch_tile_data(tile, fetcher_offset, tile_data_area, attrib.bank);
+        let next = EVENT_DOTS.iter().find(|&&dot| dot > self.cycles_line).unwrap_or(&LINE_LEN);
+        Some(next.saturating_sub(self.cycles_line).max(1) as u32)
+    }
+                                                                                       
+    fn update_lcd(&mut self) {
+        let mut bg = [BgPixel::default(); WIDTH];
 
-            let scroll_discard = self.scroll_x & 0x7;
+        // WY is compared on every line, and once it has matched the window stays armed for the rest of the frame
+        if self.win_y == self.line_y {
+            self.window_triggered = true;
+        }
 
-            for i in 0..8 {
-                if tile_num == 0 && i < scroll_discard && !window_occured {
-                    continue;
-                }
+        let window_start = self.window_start();
+        let mut fetched: Option<FetchedTile> = None;
 
-                let i = if attrib.x_flip { i } else { 7 - i };
-                let mut pixel = ((tile.1 >> i) & 1) << 1 | ((tile.0 >> i) & 1);
-                if !self.lcdc.contains(LCDC::BgWinEnable) && !self.is_cgb {
-                    pixel = 0;
+        for (x, pixel) in bg.iter_mut().enumerate() {
+            let (fetcher_x, fetcher_y, tilemap) = match window_start {
+                Some((start, column)) if x >= start => {
+                    let column = x - start + column;
+                    (column, self.win_line_counter as usize, self.lcdc.contains(LCDC::WinTileMap))
                 }
-                
-                if self.line_x >= WIDTH as u8 {
-                    break;
+                _ => {
+                    let column = x + self.scroll_x as usize;
+                    (column, self.line_y.wrapping_add(self.scroll_y) as usize, self.lcdc.contains(LCDC::BgTileMap))
                 }
-                
-                priority[self.line_x as usize] = attrib.priority;
-                pixels[self.line_x as usize] = (pixel, DMGPalette::Background);
+            };
+            let tile_x = (fetcher_x / 8) & 0x1F;
 
-
-                if self.is_cgb {
-                    let colour = self.cgb_bg_pals[(attrib.cgb_palette * 4 + pixel) as usize];
-                    //let colour = self.cgb_bg_pals[(pixel) as usize];
-                    //if tile_index == 11 {
-                    // if attrib.unwrap().priority {
-                    //   self.lcd[self.line_x as usize + self.line_y as usize * WIDTH] = 0x7888;
-                    // }
-                    // else {
-
-                        self.lcd[self.line_x as usize + self.line_y as usize * WIDTH] = colour;
-                    //}
-                    //}
-                    //self.lcd[self.line_x as usize + self.line_y as usize * WIDTH] = colour;
-                    //self.lcd[self.line_x as usize + self.line_y as usize * WIDTH] = tile_index as u16 * 0x111;
-                    //self.lcd[self.line_x as usize + self.line_y as usize * WIDTH] = attrib.unwrap().cgb_palette as u16 * 0x111;
+            // only go back to VRAM when we move onto a new tile
+            let (attrib, tile) = match fetched {
+                Some(cached) if cached.tile_x == tile_x && cached.fetcher_y == fetcher_y && cached.tilemap == tilemap => {
+                    (cached.attrib, cached.data)
                 }
+                _ => {
+                    let index = self.fetch_tile(tile_x, fetcher_y, tilemap, window_start.is_none());
+                    let attrib = if self.is_cgb { self.fetch_tile_attrib(tile_x, fetcher_y, tilemap) } else { TileAttrib::default() };
 
-                self.line_x += 1;
+                    let mut tile_y = fetcher_y % 8;
+                    if attrib.y_flip {
+                        tile_y = 7 - tile_y;
+                    }
 
-                if self.line_x >= WIDTH as u8 {
-                    break;
+                    let tile = self.fetch_tile_data(index, tile_y * 2, self.lcdc.contains(LCDC::BgTileData), attrib.bank);
+                    fetched = Some(FetchedTile { tile_x, fetcher_y, tilemap, attrib, data: tile });
+                    (attrib, tile)
                 }
+            };
+
+            let i = fetcher_x % 8;
+            let i = if attrib.x_flip { i } else { 7 - i };
+            let mut colour = ((tile.1 >> i) & 1) << 1 | ((tile.0 >> i) & 1);
+            // on DMG (and in compatibility mode) LCDC.0 blanks the background and window
+            if !self.lcdc.contains(LCDC::BgWinEnable) && !self.is_cgb {
+                colour = 0;
             }
+
+            *pixel = BgPixel {
+                colour,
+                cgb_palette: attrib.cgb_palette,
+                priority: attrib.priority,
+            };
         }
+        self.line_x = WIDTH as u8;
 
-        if self.lcdc.contains(LCDC::ObjEnable) {
-            for obj in &self.sprite_buffer {
-                let mut obj_y = (self.line_y + 16) - obj.y;
-                if obj.y_flip {
-                    if self.lcdc.contains(LCDC::ObjSize) {
-                        obj_y = 15 - obj_y;
-                    }
-                    else {
-                        obj_y = 7 - obj_y;
-                    }
-                }
-                let fetcher_offset = (obj_y % 8) * 2;
+        // the internal line counter only moves on lines where the window was actually drawn
+        if window_start.is_some() {
+            self.win_line_counter += 1;
+        }
+        self.window_full_line = window_start.is_some() && self.win_x == 166;
 
-                let mut tile = obj.tile as usize;
-                if self.lcdc.contains(LCDC::ObjSize) {
-                    if obj_y > 7 {
-                        tile |= 0x01;
-                    }
-                    else {
-                        tile &= 0xFE;
-                    }
+        let objs = if self.lcdc.contains(LCDC::ObjEnable) { self.render_objects() } else { [None; WIDTH] };
+
+        for (x, (bg, obj)) in bg.iter().zip(objs.iter()).enumerate() {
+            let obj = obj.filter(|obj| {
+                if self.is_cgb {
+                    // in CGB mode LCDC.0 clear puts every object above the background
+                    !self.lcdc.contains(LCDC::BgWinEnable) || bg.colour == 0 || !(bg.priority || obj.behind_bg)
                 }
-                let tile_index = tile;
-                let tile = self.fetch_tile_data(tile, fetcher_offset as usize, true, obj.bank);
-                
-                for offset in 0..8 { 
-                    let i = if obj.x_flip { offset } else { 7 - offset };
-                    let pixel = ((tile.1 >> i) & 1) << 1 | ((tile.0 >> i) & 1);
-                    let offset = obj.x as usize + offset as usize - 8;
-                    
-                    if offset < WIDTH && pixel != 0 {
-                        if self.is_cgb {
-                            if pixels[offset].0 == 0 || !self.lcdc.contains(LCDC::BgWinEnable) || (!obj.priority && !priority[offset]) {
-                            //if attrib.unwrap().priority {
-                                let colour = self.cgb_obj_pals[(obj.cgb_pal * 4 + pixel) as usize];
-                                //let colour = 0x7EEE;
-                                self.lcd[offset + self.line_y as usize * WIDTH] = colour;
-                            }
-                        }
-                        else {
-                            if !obj.priority || pixels[offset].0 == 0 {
-                                pixels[offset] = (pixel, obj.dmg_palette);
-                            }
-                        }
-                    }
+                else {
+                    bg.colour == 0 || !obj.behind_bg
                 }
-            }
+            });
+
+            let colour = match (obj, self.is_cgb) {
+                (Some(obj), true) => self.cgb_obj_pals[(obj.cgb_palette * 4 + obj.colour) as usize],
+                (Some(obj), false) => self.dmg_colour(obj.dmg_palette, obj.colour),
+                (None, true) => self.cgb_bg_pals[(bg.cgb_palette * 4 + bg.colour) as usize],
+                (None, false) => self.dmg_colour(DMGPalette::Background, bg.colour),
+            };
+
+            self.lcd[x + self.line_y as usize * WIDTH] = colour;
         }
+    }
 
-        if window_occured {
-            self.win_line_counter += 1;
+    /// Where the window starts on the current line, as the screen X it starts at and the
+    /// column within the window drawn there, or `None` if it isn't drawn on this line.
+    fn window_start(&self) -> Option<(usize, usize)> {
+        if !self.window_triggered || !self.lcdc.contains(LCDC::WinEnable) {
+            return None;
+        }
+        // with WX=166 on the previous line the window covers the whole of this one
+        if self.window_full_line {
+            return Some((0, 0));
         }
 
-        if !self.is_cgb {
-            for (i, pixel) in pixels.iter().enumerate() {
-                // Highlighting code
-                // if (pixel.0 & 0xF0) == 0xF0 {
-                //     let colour = 3 + pixel.0 & 0xF;
-                //     self.lcd[i + self.line_y as usize * WIDTH] = ((colour as u16) << 12) | 0x800;
-                //     continue;
-                // }
-    
-                        let palette = match pixel.1 {
-                        DMGPalette::Background => self.dmg_palettes.bg_palette,
-                        DMGPalette::Sprite0 => self.dmg_palettes.obj0_palette,
-                        DMGPalette::Sprite1 => self.dmg_palettes.obj1_palette,
-                    };
-                    let colour = (palette >> (2 * pixel.0)) & 0x3;
-                    self.lcd[i + self.line_y as usize * WIDTH] = DMG_COLOURS[colour as usize];
+        match self.win_x {
+            // the window starts part way through the fine scroll discard, so it moves with SCX
+            0 => Some((0, 7 - (self.scroll_x & 7) as usize)),
+            1..=6 => Some((0, 7 - self.win_x as usize)),
+            7..=166 => Some((self.win_x as usize - 7, 0)),
+            _ => None,
+        }
+    }
+
+    /// Works out which object wins at every position on the current line. The winner is picked
+    /// before mixing with the background, so an object hidden behind the background still hides
+    /// any lower priority objects under it.
+    fn render_objects(&self) -> [Option<ObjPixel>; WIDTH] {
+        let mut pixels = [None; WIDTH];
+        let tall = self.lcdc.contains(LCDC::ObjSize);
+
+        for obj in &self.sprite_buffer {
+            let mut obj_y = (self.line_y + 16 - obj.y) as usize;
+            if obj.y_flip {
+                obj_y = if tall { 15 } else { 7 } - obj_y;
+            }
+
+            let mut tile = obj.tile as usize;
+            if tall {
+                // bit 0 of the tile index is ignored in 8x16 mode and replaced by which half we're in
+                tile = (tile & 0xFE) | (obj_y / 8);
+            }
+            let bank = obj.bank && self.is_cgb;
+            let tile = self.fetch_tile_data(tile, (obj_y % 8) * 2, true, bank);
+
+            for offset in 0..8 {
+                let x = obj.x as i16 + offset - 8;
+                if !(0..WIDTH as i16).contains(&x) || pixels[x as usize].is_some() {
+                    continue;
+                }
+
+                let i = if obj.x_flip { offset } else { 7 - offset };
+                let colour = ((tile.1 >> i) & 1) << 1 | ((tile.0 >> i) & 1);
+                if colour == 0 {
+                    continue;
+                }
+
+                pixels[x as usize] = Some(ObjPixel {
+                    colour,
+                    dmg_palette: obj.dmg_palette,
+                    cgb_palette: obj.cgb_pal,
+                    behind_bg: obj.priority,
+                });
             }
         }
+
+        pixels
+    }
+
+    fn dmg_colour(&self, palette: DMGPalette, colour: u8) -> u16 {
+        let palette = match palette {
+            DMGPalette::Background => self.dmg_palettes.bg_palette,
+            DMGPalette::Sprite0 => self.dmg_palettes.obj0_palette,
+            DMGPalette::Sprite1 => self.dmg_palettes.obj1_palette,
+        };
+
+        DMG_COLOURS[((palette >> (2 * colour)) & 0x3) as usize]
     }
 
     pub fn dump_tiles(&self) -> [u16; WIDTH * HEIGHT] {
@@ -548,28 +757,31 @@ impl PPU {
         pixels
     }
 
+    /// Picks the first 10 objects in OAM order that cover the current line, then sorts them
+    /// into drawing priority: by X then OAM index on DMG (or with OPRI set), by OAM index alone on CGB.
     fn oam_search(&self) -> Vec<Object> {
         let mut objects = vec![];
+        let obj_height = if self.lcdc.contains(LCDC::ObjSize) { 16 } else { 8 };
 
-        for object in self.oam.chunks_exact(4) {
+        for (index, object) in self.oam.chunks_exact(4).take(40).enumerate() {
             let object = (u32::from(object[0]) << 24)
             | (u32::from(object[1]) << 16)
             | (u32::from(object[2]) << 8)
             | u32::from(object[3]);
-            let object = Object::from_bytes(object);
-            let obj_height = if self.lcdc.contains(LCDC::ObjSize) { 16 } else { 8 };
+            let object = Object::from_bytes(index as u8, object);
+            let line = self.line_y as u16 + 16;
             
-            if self.line_y + 16 >= object.y && self.line_y + 16 < object.y + obj_height {
+            if line >= object.y as u16 && line < object.y as u16 + obj_height {
                 objects.push(object);
             }
             if objects.len() == 10 {
                 break;
             }
         }
-        if !self.is_cgb {
-            objects.sort_by_key(|obj| obj.x);
+
+        if self.obj_priority_by_x || !self.is_cgb {
+            objects.sort_by_key(|obj| (obj.x, obj.index));
         }
-        objects.reverse();
         objects
     }
 
@@ -607,6 +819,8 @@ impl PPU {
                 self.status |= Mode::VBlank as u8;
                 interrupts |= Interrupts::VBlank;
                 self.win_line_counter = 0;
+                self.window_triggered = false;
+                self.window_full_line = false;
             }
 
             if self.mode != Mode::VBlank {
@@ -623,34 +837,71 @@ impl PPU {
         self.status &= 0xFC;
         self.status |= Mode::OAMScan as u8;
 
-        if self.win_y == self.line_y {
-            self.window_triggered = true;
+    }
+
+    /// LY as seen by the CPU. Line 153 only reports itself for the first M-cycle before LY wraps to 0.
+    fn ly_register(&self) -> u8 {
+        if self.line_y == FRAME_SCANLINES - 1 && self.cycles_line >= 4 {
+            0
+        }
+        else {
+            self.line_y
+        }
+    }
+
+    /// The value the LY=LYC comparator sees. It lags LY by an M-cycle at the start of each line,
+    /// during which nothing matches, and goes through 153 and then 0 on line 153.
+    fn ly_for_compare(&self) -> Option<u8> {
+        match (self.line_y, self.cycles_line) {
+            (0, _) => Some(0),
+            (153, 0..=3) => None,
+            (153, 4..=7) => Some(153),
+            (153, 8..=11) => None,
+            (153, _) => Some(0),
+            (_, 0..=3) => None,
+            (line, _) => Some(line),
         }
     }
 
+    /// The STAT interrupt line is the OR of every enabled source. `enables` is in the same layout as STAT.
+    fn stat_line(&self, enables: u8) -> bool {
+        let lyc = enables & StatReg::LycInt as u8 != 0 && self.status & StatReg::LycLy as u8 != 0;
+        let hblank = enables & StatReg::HBlankInt as u8 != 0 && self.mode == Mode::HBlank;
+        let vblank = enables & StatReg::VBlankInt as u8 != 0 && self.mode == Mode::VBlank;
+        // the mode 2 source also fires as line 144 starts, even though mode 2 is never entered
+        let oam = enables & StatReg::OamInt as u8 != 0
+            && (self.mode == Mode::OAMScan || (self.line_y == VBLANK_START && self.cycles_line < 4));
+
+        lyc || hblank || vblank || oam
+    }
+
+    fn write_stat(&mut self, value: u8) {
+        // On DMG, writing STAT briefly enables every source, which raises a spurious interrupt
+        // if the line was low and the PPU is in H-Blank, V-Blank or on a LY=LYC line.
+        if self.model == Model::Dmg && self.lcdc.contains(LCDC::PpuEnable) && !self.stat_flag {
+            let all = StatReg::HBlankInt as u8 | StatReg::VBlankInt as u8 | StatReg::LycInt as u8;
+            if self.stat_line(all) {
+                self.stat_write_glitch = true;
+            }
+        }
+
+        self.status = (value & 0x78) | (self.status & 0x07) | 0x80;
+    }
+
     fn update_stat(&mut self) -> Interrupts {
         let old_stat_flag = self.stat_flag;
 
-        let lyc = if self.line_y == self.line_compare {
+        if self.ly_for_compare() == Some(self.line_compare) {
             self.status |= StatReg::LycLy as u8;
-            if self.status & StatReg::LycInt as u8 == StatReg::LycInt as u8 {
-                true
-            }
-            else {
-                false
-            }
         }
         else {
             self.status &= !(StatReg::LycLy as u8);
-            false
-        };
-        let hblank = self.status & StatReg::HBlankInt as u8 == StatReg::HBlankInt as u8 && self.mode == Mode::HBlank;
-        let vblank = self.status & StatReg::VBlankInt as u8 == StatReg::VBlankInt as u8 && self.mode == Mode::VBlank;
-        let oam = self.status & StatReg::OamInt as u8 == StatReg::OamInt as u8 && self.mode == Mode::OAMScan;
+        }
 
-        self.stat_flag = lyc || hblank || vblank || oam;
+        self.stat_flag = self.stat_line(self.status);
 
-        if !old_stat_flag && self.stat_flag {
+        if (!old_stat_flag && self.stat_flag) || self.stat_write_glitch {
+            self.stat_write_glitch = false;
             Interrupts::LcdStat
         }
         else {
@@ -687,6 +938,22 @@ impl PPU {
         }
     }
 
+    /// The first 256 tiles on screen, going across the background map a row at a time, which is
+    /// how the SGB copies data out of VRAM.
+    pub fn screen_tile_data(&self) -> [u8; 0x1000] {
+        let mut data = [0; 0x1000];
+        let tilemap = self.lcdc.contains(LCDC::BgTileMap);
+        let tile_data_area = self.lcdc.contains(LCDC::BgTileData);
+
+        for (i, tile) in data.chunks_exact_mut(16).enumerate() {
+            let tile_index = self.fetch_tile(i % 20, (i / 20) * 8, tilemap, true);
+            let start = self.get_tile_fetch_index(tile_index, 0, tile_data_area, false);
+            tile.copy_from_slice(&self.vram[start..start + 16]);
+        }
+
+        data
+    }
+
     fn fetch_tile_data(&self, tile_index: usize, tile_offset: usize, tile_data_area: bool, bank: bool) -> (u8, u8) {
         let index = self.get_tile_fetch_index(tile_index, tile_offset, tile_data_area, bank);
         (self.vram[index], self.vram[index + 1])
@@ -696,4 +963,248 @@ impl PPU {
         let tilemap = if tilemap { 0x1C00 } else { 0x1800 };
         TileAttrib::from(self.vram[0x2000 + tilemap + (fetcher_y / 8) * 32 + fetcher_x])
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PPU that switched the LCD on and has run up to `dot` of `line`.
+    fn ppu_at(model: Model, line: u32, dot: u32) -> PPU {
+        let mut ppu = PPU::new(model);
+        ppu.run_cycles(1);
+        ppu.write_io(0xFF40, 0x80);
+        ppu.run_cycles(line * LINE_LEN as u32 + dot);
+        ppu
+    }
+
+    #[test]
+    fn vram_locked_during_mode_3() {
+        let mut ppu = ppu_at(Model::Dmg, 1, 76);
+        ppu.write_vram(0, 0x12);
+        assert_eq!(ppu.read_vram(0), 0x12);
+
+        // reads are cut off as mode 3 starts, but a write on that dot still goes through
+        ppu.run_cycles(4);
+        assert_eq!(ppu.read_vram(0), 0xFF);
+        ppu.write_vram(0, 0x34);
+        ppu.run_cycles(4);
+        ppu.write_vram(0, 0x56);
+        assert_eq!(ppu.debug_read_vram(0), 0x34);
+
+        ppu.run_cycles((HBLANK_START - 84) as u32);
+        assert_eq!(ppu.read_vram(0), 0x34);
+    }
+
+    #[test]
+    fn oam_locked_during_modes_2_and_3() {
+        for model in [Model::Dmg, Model::Cgb] {
+            let mut ppu = ppu_at(model, 1, 0);
+            ppu.dma_write_oam(0, 0x12);
+            assert_eq!(ppu.read_oam(0), 0xFF);
+            ppu.write_oam(0, 0x34);
+            assert_eq!(ppu.debug_read_oam(0), 0x12);
+
+            // DMG lets a write through as the scan hands over to mode 3
+            ppu.run_cycles(DRAW_START as u32);
+            ppu.write_oam(0, 0x56);
+            let expected = if model == Model::Dmg { 0x56 } else { 0x12 };
+            assert_eq!(ppu.debug_read_oam(0), expected, "{model:?}");
+            assert_eq!(ppu.read_oam(0), 0xFF);
+
+            ppu.run_cycles((HBLANK_START - DRAW_START) as u32);
+            assert_eq!(ppu.read_oam(0), expected);
+        }
+    }
+
+    #[test]
+    fn no_oam_lock_before_the_first_mode_3() {
+        // the first line after switching the LCD on has no OAM scan
+        let mut ppu = ppu_at(Model::Dmg, 0, 40);
+        ppu.write_oam(0, 0x12);
+        assert_eq!(ppu.read_oam(0), 0x12);
+        ppu.run_cycles(40);
+        assert_eq!(ppu.read_oam(0), 0xFF);
+
+        // and nothing is locked with the LCD off
+        ppu.write_io(0xFF40, 0x00);
+        ppu.run_cycles(1);
+        ppu.write_vram(0, 0x34);
+        assert_eq!((ppu.read_oam(0), ppu.read_vram(0)), (0x12, 0x34));
+    }
+
+    /// A PPU 40 dots into line 1's OAM scan, on row 10, with every byte of OAM different.
+    fn ppu_scanning_row_10(model: Model) -> PPU {
+        let mut ppu = ppu_at(model, 1, 40);
+        for i in 0..0xA0 {
+            ppu.dma_write_oam(i, (i as u8).wrapping_mul(0x1D) ^ 0xA5);
+        }
+        ppu
+    }
+
+    /// Checks rows `from..` hold `rows`, and the rest of OAM is untouched.
+    fn assert_oam_rows(ppu: &PPU, from: usize, rows: &[[u8; 8]]) {
+        let mut expected = ppu_scanning_row_10(Model::Dmg).oam;
+        for (i, row) in rows.iter().enumerate() {
+            expected[(from + i) * 8..(from + i + 1) * 8].copy_from_slice(row);
+        }
+        assert_eq!(ppu.oam, expected);
+    }
+
+    #[test]
+    fn oam_bug_write() {
+        let mut ppu = ppu_scanning_row_10(Model::Dmg);
+        ppu.oam_bug(OamCorruption::Write);
+        // row 9 is [8D E0 C7 DA 39 1C 73 56], row 10 starts [B5 88]
+        assert_oam_rows(&ppu, 10, &[[0xBD, 0x88, 0xC7, 0xDA, 0x39, 0x1C, 0x73, 0x56]]);
+    }
+
+    #[test]
+    fn oam_bug_read() {
+        let mut ppu = ppu_scanning_row_10(Model::Dmg);
+        ppu.oam_bug(OamCorruption::Read);
+        assert_oam_rows(&ppu, 10, &[[0xBD, 0xE8, 0xC7, 0xDA, 0x39, 0x1C, 0x73, 0x56]]);
+    }
+
+    #[test]
+    fn oam_bug_read_increase() {
+        // the row before is corrupted too, then copied over the rows either side
+        let mut ppu = ppu_scanning_row_10(Model::Dmg);
+        ppu.oam_bug(OamCorruption::ReadIncrease);
+        let row = [0xAD, 0xE8, 0xC7, 0xDA, 0x39, 0x1C, 0x73, 0x56];
+        assert_oam_rows(&ppu, 8, &[row, row, row]);
+    }
+
+    #[test]
+    fn no_oam_bug_on_cgb() {
+        let mut ppu = ppu_scanning_row_10(Model::Cgb);
+        let oam = ppu.oam;
+        for kind in [OamCorruption::Write, OamCorruption::Read, OamCorruption::ReadIncrease] {
+            ppu.oam_bug(kind);
+        }
+        assert_eq!(ppu.oam, oam);
+    }
+
+    #[test]
+    fn ly_and_coincidence_across_line_153() {
+        // LY reads 0 from the second M-cycle, while the comparator sees 153, nothing and then 0
+        for lyc in [153, 0] {
+            let mut ppu = ppu_at(Model::Dmg, 152, 0);
+            ppu.write_io(0xFF45, lyc);
+            ppu.run_cycles(LINE_LEN as u32);
+
+            for (ly, compared) in [(153, None), (0, Some(153)), (0, None), (0, Some(0))] {
+                for _ in 0..4 {
+                    let coincidence = ppu.read_io(0xFF41) & StatReg::LycLy as u8 != 0;
+                    assert_eq!((ppu.read_io(0xFF44), coincidence), (ly, compared == Some(lyc)), "LYC {lyc}, dot {}", ppu.cycles_line);
+                    ppu.run_cycles(1);
+                }
+            }
+
+            // and line 0 carries straight on
+            ppu.run_cycles(LINE_LEN as u32 - 16);
+            assert_eq!((ppu.line_y, ppu.cycles_line), (0, 0));
+            let coincidence = ppu.read_io(0xFF41) & StatReg::LycLy as u8 != 0;
+            assert_eq!((ppu.read_io(0xFF44), coincidence), (0, lyc == 0));
+        }
+    }
+
+    #[test]
+    fn stat_write_interrupt_on_dmg_only() {
+        for model in [Model::Dmg, Model::Cgb] {
+            let mut ppu = ppu_at(model, 1, 300);
+            ppu.write_io(0xFF41, 0x00);
+            let expected = if model == Model::Dmg { Interrupts::LcdStat } else { Interrupts::empty() };
+            assert_eq!(ppu.run_cycles(1), expected, "{model:?}");
+        }
+
+        // nothing fires in mode 3 off a LY=LYC line
+        let mut ppu = ppu_at(Model::Dmg, 1, 100);
+        ppu.write_io(0xFF41, 0x00);
+        assert_eq!(ppu.run_cycles(1), Interrupts::empty());
+    }
+
+    /// A PPU with the window enabled at `wy` and `wx`, that has just switched the LCD on.
+    fn window_ppu(wy: u8, wx: u8) -> PPU {
+        let mut ppu = PPU::new(Model::Dmg);
+        ppu.run_cycles(1);
+        ppu.write_io(0xFF4A, wy);
+        ppu.write_io(0xFF4B, wx);
+        ppu.write_io(0xFF40, 0xA0);
+        ppu
+    }
+
+    fn run_lines(ppu: &mut PPU, lines: u32) {
+        ppu.run_cycles(lines * LINE_LEN as u32);
+    }
+
+    #[test]
+    fn window_start_with_wx_below_7() {
+        let mut ppu = window_ppu(0, 0);
+        ppu.window_triggered = true;
+        ppu.scroll_x = 3;
+
+        // WX=0 starts during the fine scroll discard, 1-6 cut off the first columns
+        for (wx, start) in [(0, Some((0, 4))), (1, Some((0, 6))), (6, Some((0, 1))), (7, Some((0, 0))),
+                            (100, Some((93, 0))), (166, Some((159, 0))), (167, None)] {
+            ppu.win_x = wx;
+            assert_eq!(ppu.window_start(), start, "WX {wx}");
+        }
+    }
+
+    #[test]
+    fn wx_166_covers_the_next_line() {
+        let mut ppu = window_ppu(0, 166);
+        run_lines(&mut ppu, 1);
+        assert_eq!(ppu.win_line_counter, 1);
+
+        // even with the window moved off screen
+        ppu.write_io(0xFF4B, 167);
+        assert_eq!(ppu.window_start(), Some((0, 0)));
+        run_lines(&mut ppu, 1);
+        assert_eq!(ppu.win_line_counter, 2);
+
+        // but only the one line
+        assert_eq!(ppu.window_start(), None);
+        run_lines(&mut ppu, 1);
+        assert_eq!(ppu.win_line_counter, 2);
+    }
+
+    #[test]
+    fn wy_is_latched_for_the_frame() {
+        let mut ppu = window_ppu(5, 7);
+        run_lines(&mut ppu, 5);
+        assert!(!ppu.window_triggered);
+        run_lines(&mut ppu, 1);
+        assert!(ppu.window_triggered);
+
+        // moving WY past the line afterwards doesn't stop the window
+        ppu.write_io(0xFF4A, 100);
+        run_lines(&mut ppu, 4);
+        assert_eq!(ppu.win_line_counter, 5);
+
+        // until the next frame
+        run_lines(&mut ppu, (FRAME_SCANLINES - 10) as u32);
+        assert_eq!(ppu.line_y, 0);
+        assert_eq!((ppu.window_triggered, ppu.win_line_counter), (false, 0));
+    }
+
+    #[test]
+    fn window_line_counter_only_counts_drawn_lines() {
+        let mut ppu = window_ppu(0, 7);
+        run_lines(&mut ppu, 3);
+        assert_eq!(ppu.win_line_counter, 3);
+
+        // neither a disabled window nor one off the side of the screen is drawn
+        ppu.write_io(0xFF40, 0x80);
+        run_lines(&mut ppu, 2);
+        ppu.write_io(0xFF40, 0xA0);
+        ppu.write_io(0xFF4B, 200);
+        run_lines(&mut ppu, 2);
+        assert_eq!(ppu.win_line_counter, 3);
+
+        // and it picks up where it left off
+        ppu.write_io(0xFF4B, 7);
+        run_lines(&mut ppu, 3);
+        assert_eq!((ppu.line_y, ppu.win_line_counter), (10, 6));
+    }
+}
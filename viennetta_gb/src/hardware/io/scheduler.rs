@@ -0,0 +1,143 @@
+// Rather than running every component each M-cycle, the MMU lets them fall behind and only catches
+// one up when something could tell the difference: the CPU touching its registers, or an event it
+// has scheduled, like an interrupt it's going to raise. Times are in M-cycles since power on, and an
+// event at `n` happens during the nth M-cycle, so it's due once `now` has reached it.
+
+/// Something a component does at a known time, that can't wait for the CPU to look at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// TIMA being reloaded from TMA, which requests the timer interrupt.
+    TimerReload,
+    /// A falling edge of the DIV bit that steps the APU's frame sequencer.
+    FrameSequencer,
+    /// The next dot that might change the PPU's mode or STAT line.
+    Ppu,
+    /// A transfer on the serial port, which is run every M-cycle until it finishes.
+    Serial,
+}
+
+const EVENTS: usize = 4;
+
+/// The components that are caught up lazily.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    Timer,
+    Ppu,
+    Apu,
+    Serial,
+    Infrared,
+}
+
+const COMPONENTS: usize = 5;
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    now: u64,
+    events: [Option<u64>; EVENTS],
+    /// The earliest of `events`, so checking that nothing is due is a single comparison.
+    next: u64,
+    /// How far each component has been run.
+    synced: [u64; COMPONENTS],
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            next: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn advance(&mut self, m_cycles: u64) {
+        self.now += m_cycles;
+    }
+
+    /// Sets when `event` next happens, replacing any time it was already scheduled for.
+    pub fn schedule(&mut self, event: Event, at: u64) {
+        self.events[event as usize] = Some(at);
+        // it may have been the earliest and moved later
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events[event as usize] = None;
+        self.update_next();
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.events.iter().flatten().copied().min().unwrap_or(u64::MAX);
+    }
+
+    /// Takes the earliest event that's due, along with the M-cycle it happens in.
+    pub fn pop_due(&mut self) -> Option<(Event, u64)> {
+        if self.next > self.now {
+            return None;
+        }
+
+        let events = [Event::TimerReload, Event::FrameSequencer, Event::Ppu, Event::Serial];
+        let event = events.into_iter().min_by_key(|&event| self.events[event as usize].unwrap_or(u64::MAX))?;
+        let at = self.events[event as usize].take()?;
+        self.update_next();
+        Some((event, at))
+    }
+
+    /// How far `component` has been run.
+    pub fn synced(&self, component: Component) -> u64 {
+        self.synced[component as usize]
+    }
+
+    /// Marks `component` as having run up to `to`, returning how many M-cycles it needs to run to get there.
+    pub fn catch_up(&mut self, component: Component, to: u64) -> u64 {
+        let synced = &mut self.synced[component as usize];
+        let behind = to.saturating_sub(*synced);
+        *synced = (*synced).max(to);
+        behind
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_come_out_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Ppu, 20);
+        scheduler.schedule(Event::TimerReload, 10);
+        scheduler.schedule(Event::Serial, 30);
+        scheduler.cancel(Event::Serial);
+
+        scheduler.advance(5);
+        assert_eq!(scheduler.pop_due(), None);
+        scheduler.advance(100);
+        assert_eq!(scheduler.pop_due(), Some((Event::TimerReload, 10)));
+        assert_eq!(scheduler.pop_due(), Some((Event::Ppu, 20)));
+        assert_eq!(scheduler.pop_due(), None);
+    }
+
+    #[test]
+    fn rescheduling_later() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::FrameSequencer, 20);
+        scheduler.schedule(Event::FrameSequencer, 2068);
+
+        scheduler.advance(25);
+        assert_eq!(scheduler.pop_due(), None);
+        scheduler.advance(2043);
+        assert_eq!(scheduler.pop_due(), Some((Event::FrameSequencer, 2068)));
+    }
+
+    #[test]
+    fn components_catch_up_once() {
+        let mut scheduler = Scheduler::new();
+        scheduler.advance(12);
+        assert_eq!(scheduler.catch_up(Component::Apu, 10), 10);
+        assert_eq!(scheduler.catch_up(Component::Apu, 12), 2);
+        assert_eq!(scheduler.catch_up(Component::Apu, 12), 0);
+        assert_eq!(scheduler.catch_up(Component::Ppu, 12), 12);
+    }
+}
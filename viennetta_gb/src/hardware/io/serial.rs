@@ -1,12 +1,158 @@
-// TODO: this is a bare-bones implementaion for blaargs
+pub mod printer;
 
-#[derive(Default, Debug)]
+use std::fmt;
+use std::io::Write;
+
+use bitflags::bitflags;
+
+use super::Interrupts;
+use crate::hardware::Model;
+
+// The serial port shifts SB out a bit at a time while shifting the other end's bits in. Whichever
+// side has SC bit 0 set drives the clock, at 8192 Hz (or 32 times that with the CGB's fast clock),
+// and the other side waits until it's clocked. Both get the Serial interrupt once 8 bits are done.
+// What's on the other end of the wire is a `SerialDevice`, which trades whole bytes.
+
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct SerialControl: u8 {
+        const InternalClock = 1 << 0;
+        /// CGB only.
+        const FastClock = 1 << 1;
+        const Transfer = 1 << 7;
+    }
+}
+
+/// Whatever is plugged into the link port.
+pub trait SerialDevice: fmt::Debug + Send {
+    /// The Game Boy has started clocking `byte` out.
+    fn send(&mut self, byte: u8);
+
+    /// Called once the Game Boy has clocked all 8 bits of a transfer started with `send`, for the
+    /// byte the device shifted back. If it isn't there yet the transfer is held until it is.
+    fn receive(&mut self) -> Option<u8>;
+
+    /// Called every M-cycle while the Game Boy is waiting for the device to clock a transfer, with
+    /// the byte in SB. Returns the byte the device sent once it has clocked all 8 bits.
+    fn external_transfer(&mut self, byte: u8) -> Option<u8>;
+
+    /// Called with how many T-cycles at single speed have passed, for devices that keep time. It's
+    /// called every M-cycle during transfers, but in between the cycles can be saved up.
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+/// Nothing plugged in: the input line is pulled high, and nothing ever drives the clock.
+#[derive(Debug, Default)]
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn send(&mut self, _byte: u8) {}
+
+    fn receive(&mut self) -> Option<u8> {
+        Some(0xFF)
+    }
+
+    fn external_transfer(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Prints every byte sent as a character, which is how Blargg's test ROMs report their results.
+#[derive(Debug, Default)]
+pub struct StdoutDevice;
+
+impl SerialDevice for StdoutDevice {
+    fn send(&mut self, byte: u8) {
+        print!("{}", byte as char);
+        let _ = std::io::stdout().flush();
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        Some(0xFF)
+    }
+
+    fn external_transfer(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+#[derive(Debug)]
 pub struct Serial {
+    model: Model,
     data: u8,
-    control: u8, // TODO: maybe turn into a bitflags
+    control: SerialControl,
+    /// Bits still to be clocked out in an internally clocked transfer.
+    bits_left: u8,
+    device: Box<dyn SerialDevice>,
 }
 
 impl Serial {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            data: 0,
+            control: SerialControl::empty(),
+            bits_left: 0,
+            device: Box::new(Disconnected),
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    /// The bit of the system counter whose falling edge clocks an internally clocked transfer.
+    pub fn clock_bit(&self) -> u8 {
+        if self.control.contains(SerialControl::FastClock) { 3 } else { 8 }
+    }
+
+    /// Runs an M-cycle lasting `cycles` T-cycles at single speed. `clock_edge` is whether the bit
+    /// from `clock_bit` fell during it.
+    pub fn run_cycle(&mut self, cycles: u8, clock_edge: bool) -> Interrupts {
+        self.device.tick(cycles as u32);
+
+        if !self.control.contains(SerialControl::Transfer) {
+            return Interrupts::empty();
+        }
+
+        if !self.control.contains(SerialControl::InternalClock) {
+            return match self.device.external_transfer(self.data) {
+                Some(byte) => self.finish(byte),
+                None => Interrupts::empty(),
+            };
+        }
+
+        if clock_edge && self.bits_left > 0 {
+            // the incoming bits aren't known until the end, so shift in 1s until then
+            self.data = (self.data << 1) | 1;
+            self.bits_left -= 1;
+        }
+
+        if self.bits_left == 0 {
+            if let Some(byte) = self.device.receive() {
+                return self.finish(byte);
+            }
+        }
+
+        Interrupts::empty()
+    }
+
+    /// Whether a transfer is going on, which has to be run an M-cycle at a time.
+    pub fn is_transferring(&self) -> bool {
+        self.control.contains(SerialControl::Transfer)
+    }
+
+    /// Runs `cycles` T-cycles at single speed while there's no transfer.
+    pub fn run_idle(&mut self, cycles: u32) {
+        self.device.tick(cycles);
+    }
+
+    fn finish(&mut self, byte: u8) -> Interrupts {
+        self.data = byte;
+        self.control.remove(SerialControl::Transfer);
+        Interrupts::Serial
+    }
+
     pub fn read_data(&self) -> u8 {
         self.data
     }
@@ -16,14 +162,99 @@ impl Serial {
     }
 
     pub fn read_control(&self) -> u8 {
-        self.control
+        let unused = match self.model {
+            Model::Dmg => 0x7E,
+            Model::Cgb => 0x7C,
+        };
+
+        self.control.bits() | unused
     }
 
     pub fn write_control(&mut self, control: u8) {
-        if control & 0x80 == 0x80 {
-            print!("{}", self.data as char);
+        let mut control = SerialControl::from_bits_truncate(control);
+        if self.model == Model::Dmg {
+            control.remove(SerialControl::FastClock);
+        }
+
+        let starting = control.contains(SerialControl::Transfer | SerialControl::InternalClock)
+            && !self.control.contains(SerialControl::Transfer);
+        self.control = control;
+
+        if starting {
+            self.bits_left = 8;
+            self.device.send(self.data);
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        self.control = control & 1; // only set clock select as transfer has already completed
+    /// Sends back whatever it was sent last time, and clocks a transfer of `external` if set.
+    #[derive(Debug, Default)]
+    struct Echo {
+        last: u8,
+        external: Option<u8>,
     }
-}
\ No newline at end of file
+
+    impl SerialDevice for Echo {
+        fn send(&mut self, byte: u8) {
+            self.last = byte;
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            Some(self.last.wrapping_add(1))
+        }
+
+        fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+            self.last = byte;
+            self.external.take()
+        }
+    }
+
+    #[test]
+    fn internal_clock_takes_8_edges() {
+        let mut serial = Serial::new(Model::Dmg);
+        serial.connect(Box::new(Echo::default()));
+        serial.write_data(0x41);
+        serial.write_control(0x81);
+
+        for _ in 0..7 {
+            assert_eq!(serial.run_cycle(4, true), Interrupts::empty());
+            assert_eq!(serial.run_cycle(4, false), Interrupts::empty());
+        }
+        assert_eq!(serial.read_control(), 0xFF);
+
+        assert_eq!(serial.run_cycle(4, true), Interrupts::Serial);
+        assert_eq!(serial.read_data(), 0x42);
+        assert_eq!(serial.read_control(), 0x7F);
+    }
+
+    #[test]
+    fn external_clock_waits_for_device() {
+        let mut serial = Serial::new(Model::Cgb);
+        serial.write_data(0x12);
+        serial.write_control(0x80);
+
+        for _ in 0..100 {
+            assert_eq!(serial.run_cycle(4, true), Interrupts::empty());
+        }
+
+        serial.connect(Box::new(Echo { last: 0, external: Some(0x34) }));
+        assert_eq!(serial.run_cycle(4, false), Interrupts::Serial);
+        assert_eq!(serial.read_data(), 0x34);
+        assert_eq!(serial.read_control(), 0x7C);
+    }
+
+    #[test]
+    fn fast_clock_is_cgb_only() {
+        let mut serial = Serial::new(Model::Dmg);
+        serial.write_control(0x03);
+        assert_eq!(serial.clock_bit(), 8);
+
+        let mut serial = Serial::new(Model::Cgb);
+        serial.write_control(0x03);
+        assert_eq!(serial.clock_bit(), 3);
+    }
+}
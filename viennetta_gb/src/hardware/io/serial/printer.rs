@@ -0,0 +1,344 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::SerialDevice;
+use crate::hardware::io::T_CYCLES_RATE;
+
+// The Game Boy Printer talks in packets: the magic bytes 88 33, a command, a compression flag, a
+// 16-bit length, that many bytes of data, then a 16-bit checksum of everything after the magic.
+// The Game Boy then sends two more bytes, and the printer answers them with 81 to say it's there
+// and its status. Image data comes in bands of 2 rows of 20 tiles, which pile up in the printer's
+// memory until a print command prints them with a palette and exposure.
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const ALIVE: u8 = 0x81;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_BUSY: u8 = 1 << 1;
+const STATUS_IMAGE_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+pub const PRINTER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINTER_WIDTH / 8;
+const TILE_LEN: usize = 16;
+/// The most image data the printer can hold, 9 bands or a whole screen.
+const BUFFER_LEN: usize = 0x2280;
+/// How long the print head takes over a line of pixels, going by the printer's ~2.5 seconds per screen.
+const CYCLES_PER_LINE: u64 = T_CYCLES_RATE as u64 * 5 / 2 / 144;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    Alive,
+    Status,
+}
+
+/// A Game Boy Printer, which saves each page it prints as a PNG in `directory`.
+#[derive(Debug)]
+pub struct Printer {
+    directory: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    /// The byte to shift out in the next transfer.
+    response: u8,
+    status: u8,
+    /// Image data waiting to be printed.
+    buffer: Vec<u8>,
+    /// Shades of the lines printed since the last page was finished, 0 being white.
+    page: Vec<u8>,
+    pages_printed: usize,
+    busy_cycles: u64,
+}
+
+impl Printer {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            response: 0,
+            status: 0,
+            buffer: vec![],
+            page: vec![],
+            pages_printed: 0,
+            busy_cycles: 0,
+        }
+    }
+
+    pub fn pages_printed(&self) -> usize {
+        self.pages_printed
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.status;
+        if self.busy_cycles > 0 {
+            status |= STATUS_BUSY;
+        }
+        if !self.buffer.is_empty() {
+            status |= STATUS_UNPROCESSED;
+        }
+        if self.buffer.len() >= BUFFER_LEN {
+            status |= STATUS_IMAGE_FULL;
+        }
+        status
+    }
+
+    /// Takes in a byte of a packet, returning the state after it.
+    fn next_state(&mut self, byte: u8) -> State {
+        match self.state {
+            State::Magic(i) if byte != MAGIC[i] => State::Magic(if byte == MAGIC[0] { 1 } else { 0 }),
+            State::Magic(0) => State::Magic(1),
+            State::Magic(_) => {
+                self.checksum = 0;
+                self.data.clear();
+                State::Command
+            },
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            },
+            State::Compression => {
+                self.compressed = byte & 1 == 1;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::Length(0)
+            },
+            State::Length(0) => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::Length(1)
+            },
+            State::Length(_) => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.length == 0 { State::Checksum(0) } else { State::Data }
+            },
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize { State::Checksum(0) } else { State::Data }
+            },
+            State::Checksum(0) => {
+                self.checksum ^= byte as u16;
+                State::Checksum(1)
+            },
+            State::Checksum(_) => {
+                self.checksum ^= (byte as u16) << 8;
+                if self.checksum == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.run_command();
+                }
+                else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                State::Alive
+            },
+            State::Alive => State::Status,
+            State::Status => State::Magic(0),
+        }
+    }
+
+    fn run_command(&mut self) {
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            },
+            CMD_DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { std::mem::take(&mut self.data) };
+                let space = BUFFER_LEN - self.buffer.len();
+                self.buffer.extend(data.iter().take(space));
+            },
+            CMD_PRINT if self.data.len() >= 4 => {
+                let [sheets, margins, palette, exposure] = self.data[..4] else { unreachable!() };
+                self.print(sheets, margins, palette, exposure);
+            },
+            CMD_STATUS => {},
+            command => warn!("unknown printer command {command:02X}"),
+        }
+    }
+
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8, exposure: u8) {
+        // a palette of 0 seems to be treated as the usual one
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        // exposure goes from 25% lighter at 0 to 25% darker at 7F
+        let darkness = 1.0 + (((exposure & 0x7F) as f64 - 0x40 as f64) / 0x40 as f64) * 0.25;
+
+        if sheets > 0 {
+            let lines = self.buffer.len() / (TILES_PER_ROW * TILE_LEN) * 8;
+            for y in 0..lines {
+                for x in 0..PRINTER_WIDTH {
+                    let tile = &self.buffer[((y / 8) * TILES_PER_ROW + x / 8) * TILE_LEN..];
+                    let bit = 7 - x % 8;
+                    let colour = ((tile[(y % 8) * 2 + 1] >> bit) & 1) << 1 | (tile[(y % 8) * 2] >> bit) & 1;
+                    let shade = (palette >> (colour * 2)) & 3;
+                    self.page.push(((shade as f64 * 85.0 * darkness).round() as u32).min(255) as u8);
+                }
+            }
+            self.busy_cycles = lines as u64 * CYCLES_PER_LINE;
+        }
+        self.buffer.clear();
+
+        // the page is finished once there's a margin after it to tear along
+        if margins & 0x0F != 0 && !self.page.is_empty() {
+            let page = std::mem::take(&mut self.page);
+            if let Err(err) = self.save_page(&page) {
+                warn!("couldn't save the printed page: {err}");
+            }
+        }
+    }
+
+    fn save_page(&mut self, page: &[u8]) -> io::Result<()> {
+        std::fs::create_dir_all(&self.directory)?;
+        let path = self.next_page_path();
+        self.pages_printed += 1;
+
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), PRINTER_WIDTH as u32, (page.len() / PRINTER_WIDTH) as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let pixels: Vec<u8> = page.iter().map(|shade| 255 - shade).collect();
+        encoder.write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(io::Error::other)
+    }
+
+    /// The first of print-0001.png, print-0002.png... that isn't already there.
+    fn next_page_path(&self) -> PathBuf {
+        (1..).map(|n| self.directory.join(format!("print-{n:04}.png")))
+            .find(|path| !Path::exists(path))
+            .unwrap()
+    }
+}
+
+/// Undoes the printer's run length encoding. A control byte with the top bit set is followed by a
+/// byte repeated (control & 7F) + 2 times, otherwise by control + 1 bytes to copy as they are.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut bytes = data.iter();
+
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 != 0 {
+            let Some(&byte) = bytes.next() else { break };
+            out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+        }
+        else {
+            out.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+
+    out
+}
+
+impl SerialDevice for Printer {
+    fn send(&mut self, byte: u8) {
+        // the answer goes out while the byte comes in, so it can't depend on it
+        self.response = match self.state {
+            State::Alive => ALIVE,
+            State::Status => self.status(),
+            _ => 0,
+        };
+        self.state = self.next_state(byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        Some(std::mem::take(&mut self.response))
+    }
+
+    fn external_transfer(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.busy_cycles = self.busy_cycles.saturating_sub(cycles as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a whole packet, returning the two bytes the printer answered at the end.
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> [u8; 2] {
+        let mut packet = vec![command, compressed as u8];
+        packet.extend((data.len() as u16).to_le_bytes());
+        packet.extend(data);
+        let checksum = packet.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend(checksum.to_le_bytes());
+
+        for byte in MAGIC.into_iter().chain(packet) {
+            printer.send(byte);
+            assert_eq!(printer.receive(), Some(0));
+        }
+
+        [0, 0].map(|byte| {
+            printer.send(byte);
+            printer.receive().unwrap()
+        })
+    }
+
+    #[test]
+    fn decompresses() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x01, 1, 2, 0x80, 0x00]), [0xAA, 0xAA, 0xAA, 1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn status_and_checksum() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        assert_eq!(send_packet(&mut printer, CMD_INIT, false, &[]), [ALIVE, 0]);
+        assert_eq!(send_packet(&mut printer, CMD_DATA, false, &[0; 0x280]), [ALIVE, STATUS_UNPROCESSED]);
+
+        // a bad checksum is reported, and the packet ignored
+        for byte in [0x88, 0x33, CMD_INIT, 0, 0, 0, 0x12, 0x34] {
+            printer.send(byte);
+        }
+        printer.send(0);
+        printer.send(0);
+        assert_eq!(printer.receive(), Some(STATUS_CHECKSUM_ERROR | STATUS_UNPROCESSED));
+    }
+
+    #[test]
+    fn prints_page() {
+        let directory = std::env::temp_dir().join(format!("viennetta-printer-test-{}", std::process::id()));
+        let mut printer = Printer::new(&directory);
+
+        send_packet(&mut printer, CMD_INIT, false, &[]);
+        // a band of solid colour 3, compressed into runs of 129, 129, 129, 129 and 124
+        let band = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFA, 0xFF];
+        send_packet(&mut printer, CMD_DATA, true, &band);
+        send_packet(&mut printer, CMD_DATA, false, &[]);
+
+        let [_, status] = send_packet(&mut printer, CMD_PRINT, false, &[1, 0x13, 0xE4, 0x40]);
+        assert_eq!(status, STATUS_BUSY);
+        assert_eq!(printer.pages_printed(), 1);
+
+        let image = std::fs::read(directory.join("print-0001.png")).unwrap();
+        assert_eq!(&image[1..4], b"PNG");
+        std::fs::remove_dir_all(directory).unwrap();
+
+        for _ in 0..CYCLES_PER_LINE * 16 / 4 {
+            printer.tick(4);
+        }
+        assert_eq!(send_packet(&mut printer, CMD_STATUS, false, &[]), [ALIVE, 0]);
+    }
+}
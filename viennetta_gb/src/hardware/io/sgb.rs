@@ -0,0 +1,554 @@
+use log::{trace, warn};
+
+use super::ppu::{self, PPU, LcdPixels, WIDTH, HEIGHT, DMG_COLOURS};
+
+// The Super Game Boy takes commands from the game as 16 byte packets clocked out through P1: a reset
+// pulse with both rows selected, then each bit as one row being selected (P14 for a 0, P15 for a 1)
+// followed by neither, least significant bit first, and a 0 stop bit. The first byte of a command
+// is its number shifted left by 3 with how many packets it takes in the bottom bits. Commands that
+// send more than fits in packets instead copy 4KB out of VRAM, laid out as the first 256 tiles of
+// the background map, on the next frame.
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+pub type SgbPixels = [u16; SGB_WIDTH * SGB_HEIGHT];
+
+/// Where the Game Boy's screen sits inside the border.
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+const PACKET_LEN: usize = 16;
+/// The screen is coloured in 8x8 cells, each using one of the 4 palettes.
+const CELLS_X: usize = WIDTH / 8;
+const CELLS_Y: usize = HEIGHT / 8;
+const ATTR_FILE_LEN: usize = CELLS_X * CELLS_Y / 4;
+const ATTR_FILES: usize = 45;
+const SYSTEM_PALETTES: usize = 512;
+const BORDER_TILES: usize = 256;
+const BORDER_MAP_WIDTH: usize = 32;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+/// What MASK_EN hides the screen behind while the game redraws it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mask {
+    #[default]
+    None,
+    /// Keep showing the last frame.
+    Freeze,
+    Black,
+    /// Fill the screen with colour 0.
+    Colour0,
+}
+
+/// A copy out of VRAM waiting for the next frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Palettes,
+    /// Border tiles, with whether it's the upper 128.
+    BorderTiles(bool),
+    BorderMap,
+    AttrFiles,
+}
+
+#[derive(Debug)]
+pub struct Sgb {
+    /// Whether the cartridge header asks for SGB functions, without which the SGB ignores commands.
+    enabled: bool,
+    /// P14 and P15 as last written.
+    lines: u8,
+    /// Whether a packet is being clocked in, and how many bits of it have arrived.
+    receiving: bool,
+    bits: usize,
+    packet: [u8; PACKET_LEN],
+    /// The packets of the command so far.
+    command: Vec<u8>,
+    players: u8,
+    player: u8,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Box<[[u16; 4]; SYSTEM_PALETTES]>,
+    /// Which palette each cell of the screen uses.
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attr_files: Box<[u8; ATTR_FILE_LEN * ATTR_FILES]>,
+    mask: Mask,
+    frozen: Option<LcdPixels>,
+    transfer: Option<Transfer>,
+    /// SNES 4bpp tiles.
+    border_tiles: Box<[u8; BORDER_TILES * 32]>,
+    /// 32x32 tile map entries: tile number, palette in bits 10-12, and flips in bits 14 and 15.
+    border_map: [u16; BORDER_MAP_WIDTH * BORDER_MAP_WIDTH],
+    /// The border's own palettes, 4-7, where colour 0 is transparent.
+    border_palettes: [[u16; 16]; 4],
+}
+
+impl Sgb {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            lines: 0x30,
+            receiving: false,
+            bits: 0,
+            packet: [0; PACKET_LEN],
+            command: vec![],
+            players: 1,
+            player: 0,
+            palettes: [DMG_COLOURS; 4],
+            system_palettes: Box::new([[0; 4]; SYSTEM_PALETTES]),
+            attributes: [0; CELLS_X * CELLS_Y],
+            attr_files: Box::new([0; ATTR_FILE_LEN * ATTR_FILES]),
+            mask: Mask::None,
+            frozen: None,
+            transfer: None,
+            border_tiles: Box::new([0; BORDER_TILES * 32]),
+            border_map: [0; BORDER_MAP_WIDTH * BORDER_MAP_WIDTH],
+            border_palettes: [[0; 16]; 4],
+        }
+    }
+
+    pub fn mask(&self) -> Mask {
+        self.mask
+    }
+
+    /// P1 as the game sees it, which has the current player's number in it during MLT_REQ when no
+    /// row is selected. Only player 1's buttons are connected.
+    pub fn read_p1(&self, value: u8) -> u8 {
+        if self.players == 1 {
+            value
+        }
+        else if value & 0x30 == 0x30 {
+            (value & 0xF0) | (0xF - self.player)
+        }
+        else if self.player != 0 {
+            value | 0x0F
+        }
+        else {
+            value
+        }
+    }
+
+    pub fn write_p1(&mut self, value: u8) {
+        let lines = value & 0x30;
+        let old_lines = std::mem::replace(&mut self.lines, lines);
+
+        if lines == 0 {
+            self.receiving = true;
+            self.bits = 0;
+            self.packet = [0; PACKET_LEN];
+            return;
+        }
+
+        if !self.receiving {
+            // the next player is picked as P15 goes high
+            if old_lines & 0x20 == 0 && lines & 0x20 != 0 {
+                self.player = (self.player + 1) % self.players;
+            }
+            return;
+        }
+
+        // a bit is one row being selected after neither
+        if lines == 0x30 || old_lines != 0x30 {
+            return;
+        }
+
+        let bit = lines == 0x10;
+        if self.bits == PACKET_LEN * 8 {
+            self.receiving = false;
+            if !bit {
+                self.packet_received();
+            }
+            return;
+        }
+
+        if bit {
+            self.packet[self.bits / 8] |= 1 << (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    fn packet_received(&mut self) {
+        if self.command.is_empty() && self.packet[0] & 0x07 == 0 {
+            return;
+        }
+
+        self.command.extend_from_slice(&self.packet);
+        let len = self.command[0] as usize & 0x07;
+        if self.command.len() >= len * PACKET_LEN {
+            let command = std::mem::take(&mut self.command);
+            if self.enabled {
+                self.run_command(&command);
+            }
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        let command = data[0] >> 3;
+        trace!("SGB command {command:02X}");
+
+        match command {
+            PAL01 => self.set_palette_pair(0, 1, data),
+            PAL23 => self.set_palette_pair(2, 3, data),
+            PAL03 => self.set_palette_pair(0, 3, data),
+            PAL12 => self.set_palette_pair(1, 2, data),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            PAL_SET => self.pal_set(data),
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            },
+            CHR_TRN => self.transfer = Some(Transfer::BorderTiles(data[1] & 1 == 1)),
+            PCT_TRN => self.transfer = Some(Transfer::BorderMap),
+            ATTR_TRN => self.transfer = Some(Transfer::AttrFiles),
+            ATTR_SET => self.attr_set(data[1]),
+            MASK_EN => self.set_mask(match data[1] & 0x03 {
+                0 => Mask::None,
+                1 => Mask::Freeze,
+                2 => Mask::Black,
+                _ => Mask::Colour0,
+            }),
+            _ => warn!("Unimplemented SGB command {command:02X}"),
+        }
+    }
+
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let colours: Vec<u16> = data[1..15].chunks_exact(2)
+            .map(|colour| u16::from_le_bytes([colour[0], colour[1]]) & 0x7FFF)
+            .collect();
+
+        // colour 0 is shared by every palette
+        for palette in &mut self.palettes {
+            palette[0] = colours[0];
+        }
+        self.palettes[first][1..].copy_from_slice(&colours[1..4]);
+        self.palettes[second][1..].copy_from_slice(&colours[4..7]);
+    }
+
+    fn set_mask(&mut self, mask: Mask) {
+        self.mask = mask;
+        if mask != Mask::Freeze {
+            self.frozen = None;
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = data[1] as usize & 0x1F;
+        for set in data[2..].chunks_exact(6).take(sets) {
+            let control = set[0] & 0x07;
+            let inside_palette = set[1] & 0x03;
+            let border_palette = (set[1] >> 2) & 0x03;
+            let outside_palette = (set[1] >> 4) & 0x03;
+            let (left, top, right, bottom) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+
+            // with only one of inside or outside changed, the border goes with it
+            let (change_border, border_palette) = match control {
+                0x01 => (true, inside_palette),
+                0x04 => (true, outside_palette),
+                _ => (control & 0x02 != 0, border_palette),
+            };
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+                    let inside = x > left && x < right && y > top && y < bottom;
+                    let palette = if inside {
+                        (control & 0x01 != 0).then_some(inside_palette)
+                    }
+                    else if within {
+                        change_border.then_some(border_palette)
+                    }
+                    else {
+                        (control & 0x04 != 0).then_some(outside_palette)
+                    };
+
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_X + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let lines = data[1] as usize;
+        for &line in data[2..].iter().take(lines) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if number < CELLS_Y {
+                    self.attributes[number * CELLS_X..(number + 1) * CELLS_X].fill(palette);
+                }
+            }
+            else if number < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + number] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after_palette = data[1] & 0x03;
+        let before_palette = (data[1] >> 2) & 0x03;
+        let line_palette = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let divider = data[2] as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * CELLS_X + x] = match position.cmp(&divider) {
+                    std::cmp::Ordering::Less => before_palette,
+                    std::cmp::Ordering::Equal => line_palette,
+                    std::cmp::Ordering::Greater => after_palette,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 1 == 1;
+
+        for i in 0..count.min(CELLS_X * CELLS_Y) {
+            let Some(&byte) = data.get(6 + i / 4) else { break };
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+            self.attributes[y * CELLS_X + x] = (byte >> (6 - 2 * (i % 4))) & 0x03;
+
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            }
+            else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for (i, palette) in data[1..9].chunks_exact(2).enumerate() {
+            let index = u16::from_le_bytes([palette[0], palette[1]]) as usize % SYSTEM_PALETTES;
+            self.palettes[i] = self.system_palettes[index];
+        }
+        let colour_0 = self.palettes[0][0];
+        for palette in &mut self.palettes {
+            palette[0] = colour_0;
+        }
+
+        let attributes = data[9];
+        if attributes & 0x80 != 0 {
+            self.attr_set(attributes & 0x3F);
+        }
+        if attributes & 0x40 != 0 {
+            self.set_mask(Mask::None);
+        }
+    }
+
+    fn attr_set(&mut self, value: u8) {
+        let file = (value & 0x3F) as usize;
+        if file >= ATTR_FILES {
+            warn!("SGB attribute file {file} doesn't exist");
+            return;
+        }
+
+        let bytes = &self.attr_files[file * ATTR_FILE_LEN..(file + 1) * ATTR_FILE_LEN];
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (bytes[i / 4] >> (6 - 2 * (i % 4))) & 0x03;
+        }
+        if value & 0x40 != 0 {
+            self.set_mask(Mask::None);
+        }
+    }
+
+    /// Does any waiting transfer out of VRAM, and freezes the screen if it's been asked to.
+    pub fn vblank(&mut self, ppu: &PPU) {
+        if self.mask == Mask::Freeze && self.frozen.is_none() {
+            self.frozen = Some(ppu.get_frame());
+        }
+
+        let Some(transfer) = self.transfer.take() else { return };
+        let data = ppu.screen_tile_data();
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+
+        match transfer {
+            Transfer::Palettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (j, colour) in palette.iter_mut().enumerate() {
+                        *colour = read_u16(i * 8 + j * 2) & 0x7FFF;
+                    }
+                }
+            },
+            Transfer::BorderTiles(upper) => {
+                let start = if upper { data.len() } else { 0 };
+                self.border_tiles[start..start + data.len()].copy_from_slice(&data);
+            },
+            Transfer::BorderMap => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = read_u16(i * 2);
+                }
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, colour) in palette.iter_mut().enumerate() {
+                        *colour = read_u16(0x800 + i * 32 + j * 2) & 0x7FFF;
+                    }
+                }
+            },
+            Transfer::AttrFiles => self.attr_files.copy_from_slice(&data[..ATTR_FILE_LEN * ATTR_FILES]),
+        }
+    }
+
+    /// Colourises `lcd` and puts it inside the border.
+    pub fn render(&self, lcd: &LcdPixels) -> SgbPixels {
+        let backdrop = self.palettes[0][0];
+        let mut pixels = [backdrop; SGB_WIDTH * SGB_HEIGHT];
+        self.draw_border(&mut pixels);
+
+        let lcd = self.frozen.as_ref().unwrap_or(lcd);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let colour = match self.mask {
+                    Mask::Black => 0,
+                    Mask::Colour0 => backdrop,
+                    Mask::None | Mask::Freeze => {
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                        self.palettes[palette][ppu::dmg_shade(lcd[y * WIDTH + x])]
+                    },
+                };
+                pixels[(y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X] = colour;
+            }
+        }
+
+        pixels
+    }
+
+    fn draw_border(&self, pixels: &mut SgbPixels) {
+        for tile_y in 0..SGB_HEIGHT / 8 {
+            for tile_x in 0..SGB_WIDTH / 8 {
+                let entry = self.border_map[tile_y * BORDER_MAP_WIDTH + tile_x];
+                let tile = &self.border_tiles[(entry as usize & 0xFF) * 32..][..32];
+                let palette = &self.border_palettes[(entry as usize >> 10) & 0x03];
+                let flip_x = entry & 0x4000 != 0;
+                let flip_y = entry & 0x8000 != 0;
+
+                for row in 0..8 {
+                    let tile_row = if flip_y { 7 - row } else { row };
+                    let planes = [tile[tile_row * 2], tile[tile_row * 2 + 1], tile[16 + tile_row * 2], tile[16 + tile_row * 2 + 1]];
+
+                    for column in 0..8 {
+                        let bit = if flip_x { column } else { 7 - column };
+                        let colour = planes.iter().enumerate()
+                            .fold(0, |colour, (plane, byte)| colour | ((byte >> bit) & 1) << plane) as usize;
+
+                        if colour != 0 {
+                            pixels[(tile_y * 8 + row) * SGB_WIDTH + tile_x * 8 + column] = palette[colour];
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(sgb: &mut Sgb, packet: [u8; PACKET_LEN]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for i in 0..PACKET_LEN * 8 {
+            sgb.write_p1(if packet[i / 8] & (1 << (i % 8)) != 0 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    fn command(command: u8, args: &[u8]) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        packet[0] = command << 3 | 1;
+        packet[1..1 + args.len()].copy_from_slice(args);
+        packet
+    }
+
+    #[test]
+    fn palettes_and_blocks() {
+        let mut sgb = Sgb::new(true);
+        // colour 0 red, palette 0 greens and palette 1 blues
+        send_packet(&mut sgb, command(PAL01, &[0x1F, 0x00, 0xE0, 0x03, 0xE0, 0x03, 0xE0, 0x03, 0x00, 0x7C, 0x00, 0x7C, 0x00, 0x7C]));
+        // palette 1 inside and on the border of cells (1, 1) to (3, 3)
+        send_packet(&mut sgb, command(ATTR_BLK, &[1, 0x03, 0x05, 1, 1, 3, 3]));
+
+        let mut lcd = [DMG_COLOURS[3]; WIDTH * HEIGHT];
+        lcd[0] = DMG_COLOURS[0];
+        let pixels = sgb.render(&lcd);
+        let at = |x: usize, y: usize| pixels[(y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X];
+
+        assert_eq!(at(0, 0), 0x001F);
+        assert_eq!(at(1, 0), 0x03E0);
+        assert_eq!(at(8, 8), 0x7C00);
+        assert_eq!(at(31, 31), 0x7C00);
+        assert_eq!(at(32, 32), 0x03E0);
+        // the border is transparent, so it shows colour 0
+        assert_eq!(pixels[0], 0x001F);
+    }
+
+    #[test]
+    fn commands_need_sgb_header() {
+        let mut sgb = Sgb::new(false);
+        send_packet(&mut sgb, command(MASK_EN, &[2]));
+        assert_eq!(sgb.mask(), Mask::None);
+
+        let mut sgb = Sgb::new(true);
+        send_packet(&mut sgb, command(MASK_EN, &[2]));
+        assert_eq!(sgb.mask(), Mask::Black);
+    }
+
+    #[test]
+    fn multiplayer_reads() {
+        let mut sgb = Sgb::new(true);
+        send_packet(&mut sgb, command(MLT_REQ, &[1]));
+
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFE);
+        // player 2's buttons aren't connected
+        sgb.write_p1(0x20);
+        assert_eq!(sgb.read_p1(0xEE), 0xEF);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFE);
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+    }
+}
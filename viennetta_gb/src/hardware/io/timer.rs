@@ -1,41 +1,68 @@
 use super::Interrupts;
+use crate::hardware::Model;
+
+// TIMA counts the falling edges of one bit of the internal counter DIV is the top byte of, ANDed
+// with the enable bit of TAC. Because it's the edges of that AND that count, resetting DIV or
+// writing TAC can clock TIMA too, though on the CGB switching frequency while the timer stays on
+// doesn't, only turning it off does. When TIMA overflows it reads 0 for an M-cycle before it's reloaded
+// from TMA and the interrupt is requested, and writing TIMA during that M-cycle stops both. In the
+// M-cycle after the reload TIMA ignores writes, but follows writes to TMA.
+
+/// T-cycles in an M-cycle.
+const M_CYCLE: u8 = 4;
 
 #[derive(Debug, Default)]
 pub struct Timer {
+    model: Model,
     div: u16,
     tima: u8,
     modulo: u8,
     control: u8,
+    /// T-cycles until TIMA is reloaded after overflowing.
+    reload_delay: u8,
+    /// T-cycles left of the M-cycle after a reload.
+    reloading: u8,
 }
 
 impl Timer {
-    pub fn run_cycles(&mut self, cycles: u8) -> Interrupts {
-        let mut int = false;
-
-        for _ in 0..cycles {
-            let tima_bit_pos = match self.control & 0x3 {
-                0 => 9,
-                1 => 3,
-                2 => 5,
-                3 => 7,
-                _ => panic!("impossible")
-            };
-            let mut prev_tima_bit = (self.div >> tima_bit_pos) & 1;
-            prev_tima_bit &= (self.control as u16 >> 2) & 1; // get enable bit
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            ..Default::default()
+        }
+    }
 
-            self.div = self.div.wrapping_add(1);
+    pub fn run_cycles(&mut self, cycles: u32) -> Interrupts {
+        let mut int = false;
+        let mut left = cycles;
 
-            let mut post_tima_bit = (self.div >> tima_bit_pos) & 1;
-            post_tima_bit &= (self.control as u16 >> 2) & 1; // get enable bit
+        while left > 0 {
+            // until TIMA overflows all there is to do is count, which can be done in one go
+            if self.reload_delay == 0 && self.reloading == 0 {
+                let skipped = self.cycles_until_overflow().map_or(left, |cycles| cycles - 1).min(left);
+                self.skip(skipped);
+                left -= skipped;
+                if left == 0 {
+                    break;
+                }
+            }
+            left -= 1;
 
-            if prev_tima_bit == 1 && post_tima_bit == 0 { // falling edge
-                self.tima = self.tima.wrapping_add(1);
-                
-                if self.tima == 0 {  // i.e. overflown
+            self.reloading = self.reloading.saturating_sub(1);
+            if self.reload_delay > 0 {
+                self.reload_delay -= 1;
+                if self.reload_delay == 0 {
                     self.tima = self.modulo;
+                    self.reloading = M_CYCLE;
                     int = true;
                 }
             }
+
+            let tima_bit = self.tima_bit();
+            self.div = self.div.wrapping_add(1);
+            if tima_bit && !self.tima_bit() { // falling edge
+                self.increment_tima();
+            }
         }
 
         if int {
@@ -46,24 +73,110 @@ impl Timer {
         }
     }
 
+    /// Runs `cycles` T-cycles that TIMA won't overflow in.
+    fn skip(&mut self, cycles: u32) {
+        if let Some(period) = self.tima_period() {
+            let edges = (self.div as u32 % period + cycles) / period;
+            self.tima += edges as u8;
+        }
+        self.div = self.div.wrapping_add(cycles as u16);
+    }
+
+    /// T-cycles between TIMA increments, if it's enabled.
+    fn tima_period(&self) -> Option<u32> {
+        let bit = match self.control & 0x3 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            3 => 7,
+            _ => panic!("impossible")
+        };
+
+        (self.control & 0x4 != 0).then_some(2 << bit)
+    }
+
+    /// T-cycles until the one TIMA overflows in.
+    fn cycles_until_overflow(&self) -> Option<u32> {
+        let period = self.tima_period()?;
+        let first_edge = period - self.div as u32 % period;
+        Some(first_edge + (0xFF - self.tima) as u32 * period)
+    }
+
+    /// T-cycles until the one the timer interrupt is requested in, if it's going to be.
+    pub fn cycles_until_interrupt(&self) -> Option<u32> {
+        if self.reload_delay > 0 {
+            Some(self.reload_delay as u32)
+        }
+        else {
+            self.cycles_until_overflow().map(|cycles| cycles + M_CYCLE as u32)
+        }
+    }
+
+    /// The counter bit selected by TAC, ANDed with the enable bit, which TIMA counts the falling edges of.
+    fn tima_bit(&self) -> bool {
+        self.tima_period().is_some_and(|period| self.div as u32 & (period / 2) != 0)
+    }
+
+    fn increment_tima(&mut self) {
+        self.tima = self.tima.wrapping_add(1);
+        if self.tima == 0 {  // i.e. overflown
+            self.reload_delay = M_CYCLE;
+        }
+    }
+
+    /// The DIV bit whose falling edge clocks the APU's frame sequencer: bit 4 of DIV, or bit 5 in double speed.
+    pub fn div_apu_bit(&self, double_speed: bool) -> bool {
+        let bit = if double_speed { 13 } else { 12 };
+        (self.div >> bit) & 1 == 1
+    }
+
+    /// The internal counter DIV is the top half of.
+    pub fn counter(&self) -> u16 {
+        self.div
+    }
+
+    /// Bit `bit` of the internal counter DIV is the top half of, which the serial clock is taken from.
+    pub fn counter_bit(&self, bit: u8) -> bool {
+        (self.div >> bit) & 1 == 1
+    }
+
     pub fn read_io(&self, reg: u16) -> u8 {
         match reg {
             0xFF04 => (self.div >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.modulo,
-            0xFF07 => self.control,
+            0xFF07 => self.control | 0xF8,
             _ => panic!("{reg} is not a valid timer register")
         }
     }
 
     pub fn write_io(&mut self, reg: u16, value: u8) {
+        let tima_bit = self.tima_bit();
+        let stays_enabled = reg == 0xFF07 && self.control & value & 0x4 != 0;
+
         match reg {
             0xFF04 => self.div = 0,
-            0xFF05 => self.tima = value,
-            0xFF06 => self.modulo = value,
-            0xFF07 => self.control = value,
+            // the reload wins over a write in the same M-cycle, but a write before it cancels it
+            0xFF05 if self.reloading > 0 => {},
+            0xFF05 => {
+                self.tima = value;
+                self.reload_delay = 0;
+            },
+            0xFF06 => {
+                self.modulo = value;
+                if self.reloading > 0 {
+                    self.tima = value;
+                }
+            },
+            0xFF07 => self.control = value & 0x7,
             _ => panic!("{reg} is not a valid timer register")
         }
+
+        // only the DMG's multiplexer glitches when switching between two bits while enabled
+        let glitches = !stays_enabled || self.model == Model::Dmg;
+        if tima_bit && !self.tima_bit() && glitches {
+            self.increment_tima();
+        }
     }
 
     pub fn debug(&self) {
@@ -72,4 +185,104 @@ impl Timer {
         println!("TMA: {:02X}", self.modulo);
         println!("TAC: {:02X}", self.control);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A timer at 262144 Hz, which ticks every 4 M-cycles, that's about to overflow.
+    fn about_to_overflow() -> Timer {
+        let mut timer = Timer::new(Model::Dmg);
+        timer.write_io(0xFF06, 0x42);
+        timer.write_io(0xFF07, 0x05);
+        timer.write_io(0xFF05, 0xFF);
+        for _ in 0..3 {
+            assert_eq!(timer.run_cycles(4), Interrupts::empty());
+        }
+        timer
+    }
+
+    #[test]
+    fn overflow_is_delayed() {
+        let mut timer = about_to_overflow();
+        assert_eq!(timer.run_cycles(4), Interrupts::empty());
+        assert_eq!(timer.read_io(0xFF05), 0x00);
+        assert_eq!(timer.run_cycles(4), Interrupts::Timer);
+        assert_eq!(timer.read_io(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn writes_around_reload() {
+        // writing TIMA while it reads 0 cancels the reload and the interrupt
+        let mut timer = about_to_overflow();
+        timer.run_cycles(4);
+        timer.write_io(0xFF05, 0x10);
+        assert_eq!(timer.run_cycles(4), Interrupts::empty());
+        assert_eq!(timer.read_io(0xFF05), 0x10);
+
+        // but in the M-cycle after the reload it's ignored, while TMA goes straight through
+        let mut timer = about_to_overflow();
+        timer.run_cycles(8);
+        timer.write_io(0xFF05, 0x10);
+        assert_eq!(timer.read_io(0xFF05), 0x42);
+        timer.write_io(0xFF06, 0x20);
+        assert_eq!(timer.read_io(0xFF05), 0x20);
+        timer.run_cycles(4);
+        timer.write_io(0xFF05, 0x10);
+        assert_eq!(timer.read_io(0xFF05), 0x10);
+    }
+
+    #[test]
+    fn falling_edge_glitches() {
+        let mut timer = Timer::new(Model::Dmg);
+        timer.write_io(0xFF07, 0x05);
+        timer.run_cycles(8);
+
+        // bit 3 is set, so resetting DIV clocks TIMA
+        timer.write_io(0xFF04, 0);
+        assert_eq!(timer.read_io(0xFF05), 1);
+
+        // as does switching to a bit that's clear, or disabling the timer
+        timer.run_cycles(8);
+        timer.write_io(0xFF07, 0x06);
+        assert_eq!(timer.read_io(0xFF05), 2);
+        timer.write_io(0xFF07, 0x05);
+        timer.write_io(0xFF07, 0x01);
+        assert_eq!(timer.read_io(0xFF05), 3);
+
+        // but not when it was disabled already
+        timer.write_io(0xFF04, 0);
+        assert_eq!(timer.read_io(0xFF05), 3);
+    }
+
+    #[test]
+    fn cgb_frequency_switch() {
+        let mut timer = Timer::new(Model::Cgb);
+        timer.write_io(0xFF07, 0x05);
+        timer.run_cycles(8);
+
+        // switching to a bit that's clear doesn't clock TIMA on the CGB
+        timer.write_io(0xFF07, 0x06);
+        assert_eq!(timer.read_io(0xFF05), 0);
+
+        // but turning the timer off with the bit set still does
+        timer.write_io(0xFF07, 0x05);
+        timer.write_io(0xFF07, 0x01);
+        assert_eq!(timer.read_io(0xFF05), 1);
+    }
+
+    #[test]
+    fn catching_up_in_one_go() {
+        let mut stepped = about_to_overflow();
+        let mut skipped = about_to_overflow();
+        assert_eq!(skipped.cycles_until_interrupt(), Some(8));
+
+        let mut interrupts = Interrupts::empty();
+        for _ in 0..300 {
+            interrupts |= stepped.run_cycles(4);
+        }
+        assert_eq!(skipped.run_cycles(1200), interrupts);
+        assert_eq!((skipped.div, skipped.tima), (stepped.div, stepped.tima));
+    }
+}
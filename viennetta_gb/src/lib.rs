@@ -1,2 +1,5 @@
 pub mod hardware;
-pub mod disasm;
\ No newline at end of file
+pub mod disasm;
+pub mod gbs;
+pub mod link;
+pub mod movie;
\ No newline at end of file
@@ -0,0 +1,215 @@
+pub mod tcp;
+
+use std::sync::{Arc, Mutex};
+
+use crate::hardware::{GameBoy, CYCLES_PER_FRAME, io::{LcdPixels, serial::SerialDevice, infrared::ir_link}};
+
+// A link cable between two Game Boys in the same process. The two are run in lockstep, always
+// stepping whichever is further behind, so neither gets more than an instruction ahead of the
+// other. Each end of the cable knows both consoles' clocks, so a transfer is only finished once
+// the other side has caught up to it, which makes the handshakes come out the same as on hardware.
+
+/// A transfer being clocked by one side, waiting on the other.
+#[derive(Debug, Clone, Copy)]
+struct Transfer {
+    byte: u8,
+    started: u64,
+    /// What was in the other side's SB when it saw the transfer start.
+    reply: Option<u8>,
+    /// When the sending side finished clocking it.
+    finished: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Wire {
+    /// Each side's clock, in T-cycles at single speed.
+    cycles: [u64; 2],
+    /// Transfers clocked by each side.
+    transfers: [Option<Transfer>; 2],
+}
+
+/// One end of a link cable, made by `link_cable`.
+#[derive(Debug)]
+pub struct LinkPort {
+    side: usize,
+    wire: Arc<Mutex<Wire>>,
+}
+
+impl SerialDevice for LinkPort {
+    fn send(&mut self, byte: u8) {
+        let mut wire = self.wire.lock().unwrap();
+        let started = wire.cycles[self.side];
+        wire.transfers[self.side] = Some(Transfer { byte, started, reply: None, finished: None });
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        let mut wire = self.wire.lock().unwrap();
+        let now = wire.cycles[self.side];
+        let peer_cycles = wire.cycles[1 - self.side];
+        let Some(transfer) = wire.transfers[self.side].as_mut() else { return Some(0xFF) };
+
+        match transfer.reply {
+            Some(reply) => {
+                transfer.finished = Some(now);
+                Some(reply)
+            },
+            // wait for the other side to catch up in case it's about to start listening
+            None if peer_cycles < now => None,
+            None => {
+                wire.transfers[self.side] = None;
+                Some(0xFF)
+            },
+        }
+    }
+
+    fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+        let mut wire = self.wire.lock().unwrap();
+        let now = wire.cycles[self.side];
+        let peer = 1 - self.side;
+        let transfer = wire.transfers[peer].as_mut()?;
+
+        if transfer.reply.is_none() && now >= transfer.started {
+            transfer.reply = Some(byte);
+        }
+
+        match transfer.finished {
+            Some(finished) if now >= finished => {
+                let byte = transfer.byte;
+                wire.transfers[peer] = None;
+                Some(byte)
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Makes the two ends of a link cable. `LinkedGameBoys` is the easiest way to use them, but
+/// whatever runs the two Game Boys has to keep `cycles` up to date with `set_cycles`.
+pub fn link_cable() -> (LinkPort, LinkPort) {
+    let wire = Arc::new(Mutex::new(Wire::default()));
+    (LinkPort { side: 0, wire: wire.clone() }, LinkPort { side: 1, wire })
+}
+
+impl LinkPort {
+    /// Tells the cable how far this side's Game Boy has run, in T-cycles at single speed.
+    pub fn set_cycles(&self, cycles: u64) {
+        self.wire.lock().unwrap().cycles[self.side] = cycles;
+    }
+}
+
+/// Two Game Boys connected by a link cable, and with their IR ports pointed at each other, run in lockstep.
+#[derive(Debug)]
+pub struct LinkedGameBoys {
+    pub gameboys: [GameBoy; 2],
+    cycles: [u64; 2],
+    wire: Arc<Mutex<Wire>>,
+}
+
+impl LinkedGameBoys {
+    pub fn new(mut left: GameBoy, mut right: GameBoy) -> Self {
+        let (left_port, right_port) = link_cable();
+        let wire = left_port.wire.clone();
+        left.connect_serial(Box::new(left_port));
+        right.connect_serial(Box::new(right_port));
+
+        let (left_ir, right_ir) = ir_link();
+        left.connect_infrared(Box::new(left_ir));
+        right.connect_infrared(Box::new(right_ir));
+
+        Self {
+            gameboys: [left, right],
+            cycles: [0; 2],
+            wire,
+        }
+    }
+
+    /// Runs both for `cycles` T-cycles at single speed.
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let target = self.cycles[0].min(self.cycles[1]) + cycles;
+
+        while self.cycles[0] < target || self.cycles[1] < target {
+            let side = if self.cycles[0] <= self.cycles[1] { 0 } else { 1 };
+            let gameboy = &mut self.gameboys[side];
+            let cycles = gameboy.run_instruction() as u64;
+            self.cycles[side] += if gameboy.cpu.double_speed { cycles * 2 } else { cycles * 4 };
+            self.wire.lock().unwrap().cycles[side] = self.cycles[side];
+        }
+    }
+
+    pub fn run_frame(&mut self) -> [LcdPixels; 2] {
+        self.run_cycles(CYCLES_PER_FRAME as u64);
+        for gameboy in &mut self.gameboys {
+            gameboy.mmu.sync_all();
+        }
+        self.gameboys.each_ref().map(|gameboy| gameboy.mmu.get_frame())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hardware::{Model, io::{Interrupts, cart::Cartridge}};
+
+    /// A Game Boy running a program that sends `byte` with SC set to `control`, then stores what
+    /// it got back at C000.
+    pub(crate) fn transfer_program(byte: u8, control: u8) -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x116].copy_from_slice(&[
+            0x3E, byte, 0xE0, 0x01,         // ld a, byte; ldh [SB], a
+            0x3E, control, 0xE0, 0x02,      // ld a, control; ldh [SC], a
+            0xF0, 0x02, 0xCB, 0x7F,         // .wait: ldh a, [SC]; bit 7, a
+            0x20, 0xFA,                     // jr nz, .wait
+            0xF0, 0x01, 0xEA, 0x00, 0xC0,   // ldh a, [SB]; ld [$C000], a
+            0x00, 0x18, 0xFE,               // nop; .loop: jr .loop
+        ]);
+
+        let mut gameboy = GameBoy::with_model(Cartridge::new(&rom), Model::Dmg);
+        gameboy.mmu.write_memory(0xFF50, 1);
+        gameboy.cpu.regs.pc = 0x100;
+        gameboy.cpu.regs.sp = 0xFFFE;
+        gameboy
+    }
+
+    #[test]
+    fn exchanges_bytes() {
+        let mut linked = LinkedGameBoys::new(transfer_program(0x42, 0x81), transfer_program(0x99, 0x80));
+        linked.run_frame();
+
+        let [master, slave] = &mut linked.gameboys;
+        assert_eq!(master.mmu.read_memory(0xC000), 0x99);
+        assert_eq!(slave.mmu.read_memory(0xC000), 0x42);
+        assert!(master.mmu.int_flag.contains(Interrupts::Serial));
+        assert!(slave.mmu.int_flag.contains(Interrupts::Serial));
+    }
+
+    #[test]
+    fn nobody_listening() {
+        let mut linked = LinkedGameBoys::new(transfer_program(0x42, 0x81), transfer_program(0x99, 0x00));
+        linked.run_frame();
+
+        let [master, slave] = &mut linked.gameboys;
+        assert_eq!(master.mmu.read_memory(0xC000), 0xFF);
+        assert_eq!(slave.mmu.read_memory(0xC000), 0x99);
+        assert!(!slave.mmu.int_flag.contains(Interrupts::Serial));
+    }
+
+    #[test]
+    fn both_sides_finish_together() {
+        let mut linked = LinkedGameBoys::new(transfer_program(0x42, 0x81), transfer_program(0x99, 0x80));
+
+        let mut finished = [None; 2];
+        while linked.cycles[0] < CYCLES_PER_FRAME as u64 {
+            linked.run_cycles(4);
+            for side in 0..2 {
+                if finished[side].is_none() && linked.gameboys[side].mmu.int_flag.contains(Interrupts::Serial) {
+                    finished[side] = Some(linked.cycles[side]);
+                }
+            }
+        }
+
+        let [Some(master), Some(slave)] = finished else { panic!("transfer didn't finish") };
+        // 8 bits at 8192 Hz
+        assert!((4000..=4200).contains(&master), "took {master} cycles");
+        assert!(master.abs_diff(slave) <= 24);
+    }
+}
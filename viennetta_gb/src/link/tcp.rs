@@ -0,0 +1,232 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::hardware::io::serial::SerialDevice;
+
+// A link cable tunnelled over TCP to another emulator. The two can't run in lockstep like
+// `LinkedGameBoys`, so each message carries the sender's cycle count instead. Both sides say hello
+// as they connect and only start counting once the other's hello arrives, so the counts start
+// together, give or take the network's latency. The side driving
+// the clock sends its byte as it starts, then holds the transfer until the reply comes back,
+// stalling for however long the network takes. The other side picks the byte up once its own clock
+// has caught up to the start, or turns it down if it wasn't listening for the whole transfer.
+
+const MESSAGE_LEN: usize = 10;
+const MSG_START: u8 = 0x01;
+const MSG_REPLY: u8 = 0x02;
+const MSG_HELLO: u8 = 0x03;
+/// How long a transfer at 8192 Hz takes, after which a peer that wasn't listening sends back 0xFF.
+const TRANSFER_CYCLES: u64 = 8 * 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Message {
+    kind: u8,
+    byte: u8,
+    cycle: u64,
+}
+
+impl Message {
+    fn to_bytes(self) -> [u8; MESSAGE_LEN] {
+        let mut bytes = [0; MESSAGE_LEN];
+        bytes[0] = self.kind;
+        bytes[1] = self.byte;
+        bytes[2..].copy_from_slice(&self.cycle.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; MESSAGE_LEN]) -> Self {
+        Self {
+            kind: bytes[0],
+            byte: bytes[1],
+            cycle: u64::from_le_bytes(bytes[2..].try_into().unwrap()),
+        }
+    }
+}
+
+/// One end of a link cable to another emulator over TCP.
+#[derive(Debug)]
+pub struct TcpLink {
+    stream: TcpStream,
+    messages: Receiver<Message>,
+    /// Our clock, in T-cycles at single speed since the peer's hello.
+    cycles: u64,
+    /// Whether the peer's hello has arrived, and so whether `cycles` is running.
+    synced: bool,
+    /// A transfer the peer has started, waiting for us.
+    start: Option<Message>,
+    /// The peer's reply to the transfer we're clocking.
+    reply: Option<u8>,
+    /// Whether the Game Boy was waiting for a transfer last cycle.
+    listening: bool,
+    connected: bool,
+}
+
+impl TcpLink {
+    /// Waits for another emulator to connect on `addr`.
+    pub fn host(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Self::from_stream(stream)
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+
+        // read on another thread so the emulator never blocks on the network
+        let (sender, messages) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            let mut bytes = [0; MESSAGE_LEN];
+            while reader.read_exact(&mut bytes).is_ok() {
+                if sender.send(Message::from_bytes(bytes)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut link = Self {
+            stream,
+            messages,
+            cycles: 0,
+            synced: false,
+            start: None,
+            reply: None,
+            listening: false,
+            connected: true,
+        };
+        link.write(MSG_HELLO, 0);
+        Ok(link)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn write(&mut self, kind: u8, byte: u8) {
+        let message = Message { kind, byte, cycle: self.cycles };
+        if self.stream.write_all(&message.to_bytes()).is_err() {
+            self.connected = false;
+        }
+    }
+
+    fn poll_messages(&mut self) {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) if message.kind == MSG_START => self.start = Some(message),
+                Ok(message) if message.kind == MSG_REPLY => self.reply = Some(message.byte),
+                Ok(message) if message.kind == MSG_HELLO => self.synced = true,
+                Ok(_) => {},
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.connected = false;
+                    break;
+                },
+            }
+        }
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn send(&mut self, byte: u8) {
+        self.reply = None;
+        self.write(MSG_START, byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.poll_messages();
+        match self.reply.take() {
+            Some(reply) => Some(reply),
+            None if self.connected => None,
+            None => Some(0xFF),
+        }
+    }
+
+    fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+        self.listening = true;
+
+        // a peer that's behind us started it in our past, but one that's ahead has to be waited for
+        let start = self.start.filter(|start| start.cycle <= self.cycles)?;
+        self.start = None;
+        self.write(MSG_REPLY, byte);
+        Some(start.byte)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.synced {
+            self.cycles += cycles as u64;
+        }
+        self.poll_messages();
+
+        let listening = std::mem::take(&mut self.listening);
+        if let Some(start) = self.start {
+            if !listening && self.cycles > start.cycle + TRANSFER_CYCLES {
+                self.start = None;
+                self.write(MSG_REPLY, 0xFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::tests::transfer_program;
+
+    #[test]
+    fn message_round_trip() {
+        let message = Message { kind: MSG_START, byte: 0x42, cycle: 0x123456789 };
+        assert_eq!(Message::from_bytes(message.to_bytes()), message);
+    }
+
+    #[test]
+    fn clocks_start_at_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut link = TcpLink::from_stream(listener.accept().unwrap().0).unwrap();
+
+        // however long we ran before the peer said hello doesn't count
+        link.tick(1_000_000);
+        peer.write_all(&Message { kind: MSG_HELLO, byte: 0, cycle: 0 }.to_bytes()).unwrap();
+        peer.write_all(&Message { kind: MSG_START, byte: 0x42, cycle: 100 }.to_bytes()).unwrap();
+        while link.start.is_none() {
+            thread::sleep(std::time::Duration::from_millis(1));
+            link.tick(0);
+        }
+
+        assert_eq!(link.external_transfer(0x99), None);
+        link.tick(100);
+        assert_eq!(link.external_transfer(0x99), Some(0x42));
+    }
+
+    #[test]
+    fn localhost() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || TcpLink::connect(addr).unwrap());
+        let host = TcpLink::from_stream(listener.accept().unwrap().0).unwrap();
+        let client = client.join().unwrap();
+
+        let run = |link: TcpLink, byte, control| thread::spawn(move || {
+            let mut gameboy = transfer_program(byte, control);
+            gameboy.connect_serial(Box::new(link));
+            // the other side may not have started yet, so give it plenty of time
+            for _ in 0..3000 {
+                gameboy.run_frame();
+                if gameboy.mmu.read_memory(0xC000) != 0 {
+                    break;
+                }
+            }
+            gameboy.mmu.read_memory(0xC000)
+        });
+
+        let master = run(host, 0x42, 0x81);
+        let slave = run(client, 0x99, 0x80);
+        assert_eq!(master.join().unwrap(), 0x99);
+        assert_eq!(slave.join().unwrap(), 0x42);
+    }
+}
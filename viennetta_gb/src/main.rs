@@ -1,18 +1,63 @@
 use std::collections::HashSet;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{env, fs, io};
 use std::io::{stdin, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use viennetta_gb::hardware::io::cart::Cartridge;
-use viennetta_gb::hardware::GameBoy;
-use viennetta_gb::hardware::io::joypad::Buttons;
+use viennetta_gb::hardware::{GameBoy, Model};
+use viennetta_gb::hardware::io::apu::AudioSource;
+use viennetta_gb::hardware::io::serial::StdoutDevice;
 use viennetta_gb::disasm::disasm;
+use viennetta_gb::gbs::Gbs;
+
+/// Value of the option following `flag`, if given.
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    Some(args.get(i + 1).unwrap_or_else(|| panic!("{flag} needs a value")))
+}
+
+/// Renders a song from a GBS file to a WAV file without any window or sound output.
+/// Options are `--track <n>` (counting from 1), `--seconds <n>`, `--wav <path>` and `--dmg`.
+fn render_gbs(args: &[String], file: &[u8]) {
+    let gbs = Gbs::parse(file).unwrap_or_else(|err| panic!("{} isn't a valid GBS file: {err}", args[1]));
+    let header = &gbs.header;
+    println!("{} - {} ({}), {} songs", header.title, header.author, header.copyright, header.song_count);
+
+    let track: u8 = arg_value(args, "--track").map_or(header.first_song, |track| track.parse().expect("--track needs a number"));
+    if track == 0 || track > header.song_count {
+        panic!("track {track} doesn't exist, there are {} songs", header.song_count);
+    }
+    let seconds: u32 = arg_value(args, "--seconds").map_or(60, |seconds| seconds.parse().expect("--seconds needs a number"));
+    let default_path = Path::new(&args[1]).with_extension(format!("{track}.wav"));
+    let path = arg_value(args, "--wav").map_or(default_path, PathBuf::from);
+    let model = if args.contains(&"--dmg".to_string()) { Model::Dmg } else { Model::Cgb };
+
+    let mut gameboy = gbs.load_song(track - 1, model);
+    gameboy.start_recording(&path, AudioSource::Mixed).expect("couldn't create the WAV file");
+
+    let total_samples = seconds as usize * gameboy.sample_rate() as usize * 2;
+    let mut samples = 0;
+    while samples < total_samples {
+        gameboy.run_frame();
+        samples += gameboy.mmu.apu.sample_buf.len();
+        gameboy.mmu.apu.sample_buf.clear();
+    }
+
+    gameboy.stop_recording().expect("couldn't finish the WAV file");
+    println!("Rendered track {track} to {}", path.display());
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let rom = fs::read(&args[1]).expect(format!("{} is not a valid path\n", args[1]).as_str());
+
+    if args[1].to_lowercase().ends_with(".gbs") {
+        render_gbs(&args, &rom);
+        return;
+    }
+
     let mut gameboy = GameBoy::new(Cartridge::new(&rom));
 
     let mut breakpoint: HashSet<u16> = HashSet::new();
@@ -21,8 +66,6 @@ fn main() {
     let mut blaargs = false;
     let mut trace = false;
 
-    gameboy.mmu.joypad.update_state(Buttons::from_bits(0).unwrap());
-
     if args.contains(&"--debugger".to_string()) {
         //stepping = true;
         debugging = true;
@@ -38,6 +81,7 @@ fn main() {
 
     if args.contains(&"--blaargs".to_string()) {
         blaargs = true;
+        gameboy.connect_serial(Box::new(StdoutDevice));
     }
 
     if args.contains(&"--trace".to_string()) {
@@ -56,6 +100,7 @@ fn main() {
             }
             else {
                 stepping.store(false, Ordering::SeqCst);
+                gameboy.mmu.sync_all();
                 println!("{:04X}: {}", gameboy.cpu.regs.pc, disasm(gameboy.cpu.regs.pc, &gameboy.mmu));
                 loop {
                     let mut command = String::new();
@@ -0,0 +1,267 @@
+use std::fmt::{self, Write};
+
+use crate::hardware::{GameBoy, Model, io::{LcdPixels, cart::Cartridge, joypad::Buttons}};
+
+// Movies are the buttons held on each frame from power on, which is enough to replay a session
+// exactly as everything else is deterministic (apart from the MBC3 clock). They're saved as text,
+// a header of settings followed by an input log in the style of BizHawk's .bk2 files:
+//
+//     ViennettaMovie 1
+//     Model CGB
+//     RomTitle TETRIS
+//     RomChecksum 3F2A
+//     StartsFrom PowerOn
+//     [Input]
+//     LogKey:#Up|Down|Left|Right|Start|Select|B|A|
+//     |U......A|
+//     [/Input]
+
+const VERSION: u32 = 1;
+const LOG_KEY: &str = "LogKey:#Up|Down|Left|Right|Start|Select|B|A|";
+/// The buttons in the order they're logged, with the letter each is logged as.
+const LOG_BUTTONS: [(Buttons, char); 8] = [
+    (Buttons::Up, 'U'), (Buttons::Down, 'D'), (Buttons::Left, 'L'), (Buttons::Right, 'R'),
+    (Buttons::Start, 'S'), (Buttons::Select, 's'), (Buttons::B, 'B'), (Buttons::A, 'A'),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    /// The line number and what's wrong with it.
+    BadLine(usize, String),
+    MissingField(&'static str),
+    UnsupportedVersion(u32),
+    /// Only movies starting from power on are supported, as there aren't save states.
+    UnsupportedStart(String),
+    WrongRom { expected: u16, found: u16 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadLine(line, problem) => write!(f, "line {line}: {problem}"),
+            Self::MissingField(field) => write!(f, "the movie has no {field}"),
+            Self::UnsupportedVersion(version) => write!(f, "movie version {version} isn't supported"),
+            Self::UnsupportedStart(start) => write!(f, "movies starting from {start} aren't supported"),
+            Self::WrongRom { expected, found } => write!(f, "the movie was recorded on a ROM with checksum {expected:04X}, not {found:04X}"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+/// Adds up every byte of the ROM apart from the checksum itself, like the global checksum in the
+/// header, but without trusting the header to be right.
+pub fn rom_checksum(rom: &[u8]) -> u16 {
+    rom.iter().enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
+
+fn rom_title(rom: &[u8]) -> String {
+    let title = rom.get(0x134..0x144).unwrap_or_default();
+    let len = title.iter().position(|&c| c == 0).unwrap_or(title.len());
+    String::from_utf8_lossy(&title[..len]).trim().to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub model: Model,
+    pub rom_title: String,
+    pub rom_checksum: u16,
+    /// The buttons held during each frame.
+    pub frames: Vec<Buttons>,
+}
+
+impl Movie {
+    /// Starts an empty movie of `rom` on `model`.
+    pub fn new(rom: &[u8], model: Model) -> Self {
+        Self {
+            model,
+            rom_title: rom_title(rom),
+            rom_checksum: rom_checksum(rom),
+            frames: vec![],
+        }
+    }
+
+    /// Powers on a Game Boy ready to play the movie back from its first frame.
+    pub fn start(&self, rom: &[u8]) -> Result<GameBoy, MovieError> {
+        let found = rom_checksum(rom);
+        if found != self.rom_checksum {
+            return Err(MovieError::WrongRom { expected: self.rom_checksum, found });
+        }
+
+        Ok(GameBoy::with_model(Cartridge::new(rom), self.model))
+    }
+
+    /// Runs a frame with `buttons` held, adding it to the movie.
+    pub fn record_frame(&mut self, gameboy: &mut GameBoy, buttons: Buttons) -> LcdPixels {
+        self.frames.push(buttons);
+        gameboy.mmu.joypad.set_pressed(buttons);
+        gameboy.run_frame()
+    }
+
+    /// Runs frame `frame` of the movie, or returns `None` if it's already finished.
+    pub fn play_frame(&self, gameboy: &mut GameBoy, frame: usize) -> Option<LcdPixels> {
+        gameboy.mmu.joypad.set_pressed(*self.frames.get(frame)?);
+        Some(gameboy.run_frame())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut version = None;
+        let mut model = None;
+        let mut rom_title = String::new();
+        let mut rom_checksum = None;
+        let mut frames = vec![];
+        let mut in_input = false;
+
+        for (i, line) in text.lines().enumerate() {
+            let bad_line = |problem: &str| MovieError::BadLine(i + 1, problem.to_string());
+            let line = line.trim_end();
+
+            if in_input {
+                match line {
+                    "[/Input]" => in_input = false,
+                    _ if line.starts_with("LogKey:") => {},
+                    _ => frames.push(Self::parse_frame(line).ok_or_else(|| bad_line("not a frame of input"))?),
+                }
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "" => {},
+                "[Input]" => in_input = true,
+                "ViennettaMovie" => version = Some(value.parse().map_err(|_| bad_line("bad version"))?),
+                "Model" => model = Some(match value {
+                    "DMG" => Model::Dmg,
+                    "CGB" => Model::Cgb,
+                    _ => return Err(bad_line("model isn't DMG or CGB")),
+                }),
+                "RomTitle" => rom_title = value.to_string(),
+                "RomChecksum" => rom_checksum = Some(u16::from_str_radix(value, 16).map_err(|_| bad_line("bad checksum"))?),
+                "StartsFrom" if value != "PowerOn" => return Err(MovieError::UnsupportedStart(value.to_string())),
+                "StartsFrom" => {},
+                _ => return Err(bad_line("unknown field")),
+            }
+        }
+
+        match version {
+            None => return Err(MovieError::MissingField("ViennettaMovie version")),
+            Some(version) if version != VERSION => return Err(MovieError::UnsupportedVersion(version)),
+            _ => {},
+        }
+
+        Ok(Self {
+            model: model.ok_or(MovieError::MissingField("Model"))?,
+            rom_title,
+            rom_checksum: rom_checksum.ok_or(MovieError::MissingField("RomChecksum"))?,
+            frames,
+        })
+    }
+
+    /// A frame like `|U......A|`.
+    fn parse_frame(line: &str) -> Option<Buttons> {
+        let log = line.strip_prefix('|')?.strip_suffix('|')?;
+        if log.chars().count() != LOG_BUTTONS.len() {
+            return None;
+        }
+
+        log.chars().zip(LOG_BUTTONS).try_fold(Buttons::empty(), |buttons, (c, (button, letter))| match c {
+            '.' => Some(buttons),
+            _ if c == letter => Some(buttons | button),
+            _ => None,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let model = match self.model {
+            Model::Dmg => "DMG",
+            Model::Cgb => "CGB",
+        };
+
+        let mut text = String::new();
+        writeln!(text, "ViennettaMovie {VERSION}").unwrap();
+        writeln!(text, "Model {model}").unwrap();
+        writeln!(text, "RomTitle {}", self.rom_title).unwrap();
+        writeln!(text, "RomChecksum {:04X}", self.rom_checksum).unwrap();
+        writeln!(text, "StartsFrom PowerOn").unwrap();
+        writeln!(text, "[Input]").unwrap();
+        writeln!(text, "{LOG_KEY}").unwrap();
+        for buttons in &self.frames {
+            let log: String = LOG_BUTTONS.iter()
+                .map(|&(button, letter)| if buttons.contains(button) { letter } else { '.' })
+                .collect();
+            writeln!(text, "|{log}|").unwrap();
+        }
+        writeln!(text, "[/Input]").unwrap();
+
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM that keeps adding the d-pad bits of P1 to C000, so the result depends on exactly
+    /// when each button was pressed.
+    fn input_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        rom[0x100..0x110].copy_from_slice(&[
+            0x3E, 0x20, 0xE0, 0x00,         // ld a, $20; ldh [P1], a
+            0xF0, 0x00, 0x47,               // .loop: ldh a, [P1]; ld b, a
+            0xFA, 0x00, 0xC0, 0x80,         // ld a, [$C000]; add b
+            0xEA, 0x00, 0xC0,               // ld [$C000], a
+            0x18, 0xF4,                     // jr .loop
+        ]);
+        rom
+    }
+
+    fn start(movie: &Movie, rom: &[u8]) -> GameBoy {
+        let mut gameboy = movie.start(rom).unwrap();
+        gameboy.mmu.write_memory(0xFF50, 1);
+        gameboy.cpu.regs.pc = 0x100;
+        gameboy
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut movie = Movie::new(&input_rom(), Model::Dmg);
+        movie.frames = vec![Buttons::empty(), Buttons::Up | Buttons::A, Buttons::all()];
+
+        let text = movie.to_text();
+        assert!(text.contains("|U......A|\n|UDLRSsBA|"));
+        assert_eq!(Movie::parse(&text), Ok(movie));
+    }
+
+    #[test]
+    fn rejects_other_roms() {
+        let movie = Movie::new(&input_rom(), Model::Cgb);
+        let mut rom = input_rom();
+        rom[0x200] = 1;
+        assert!(matches!(movie.start(&rom), Err(MovieError::WrongRom { .. })));
+    }
+
+    #[test]
+    fn plays_back_exactly() {
+        let rom = input_rom();
+        let mut movie = Movie::new(&rom, Model::Dmg);
+        let mut gameboy = start(&movie, &rom);
+        for frame in 0..8u32 {
+            let buttons = Buttons::from_bits_truncate((frame * 37) as u8);
+            movie.record_frame(&mut gameboy, buttons);
+        }
+        let recorded = gameboy.mmu.read_memory(0xC000);
+
+        let movie = Movie::parse(&movie.to_text()).unwrap();
+        let mut gameboy = start(&movie, &rom);
+        let mut frame = 0;
+        while movie.play_frame(&mut gameboy, frame).is_some() {
+            frame += 1;
+        }
+
+        assert_eq!(frame, 8);
+        assert_eq!(gameboy.mmu.read_memory(0xC000), recorded);
+    }
+}